@@ -1,8 +1,10 @@
 use anyhow::{Context, Result};
-use ncsync_lib::cli::{login_request, poll};
+use ncsync_lib::cli::{login_request, poll, reauth};
 use ncsync_lib::communicate::ls;
+use ncsync_lib::login::LoginOption;
 use ncsync_lib::setting::readwrite::setting_from_toml;
 use ncsync_lib::setting::{ClientHub, LocalInfo, LoginStatus::*};
+use ncsync_lib::NcsError;
 use std::env;
 
 #[tokio::main]
@@ -37,7 +39,28 @@ async fn main() -> Result<()> {
             poll(&profile_name, &mut hub, &client_hub_file_path).await?;
             loggedin(&profile_name, &hub, &local_info, &target).await?;
         }
-        LoggedIn { .. } => loggedin(&profile_name, &hub, &local_info, &target).await?,
+        LoggedIn { .. } | OAuth2 { .. } => {
+            let res = loggedin(&profile_name, &hub, &local_info, &target).await;
+            if let Some(NcsError::NotAuthorized) = res.as_ref().err().and_then(|e| e.downcast_ref())
+            {
+                // app passwordが失効していたらログインし直して再開する
+                let option = LoginOption {
+                    open_browser: true,
+                    ..Default::default()
+                };
+                reauth(
+                    &profile_name,
+                    &mut hub,
+                    &client_hub_file_path,
+                    &option,
+                    None,
+                )
+                .await?;
+                loggedin(&profile_name, &hub, &local_info, &target).await?;
+            } else {
+                res?;
+            }
+        }
     }
 
     Ok(())
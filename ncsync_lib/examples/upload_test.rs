@@ -39,7 +39,9 @@ async fn main() -> Result<()> {
             poll(&profile_name, &mut hub, &client_hub_file_path).await?;
             loggedin(&profile_name, &hub, &local_info, &resource, &target).await?;
         }
-        LoggedIn { .. } => loggedin(&profile_name, &hub, &local_info, &resource, &target).await?,
+        LoggedIn { .. } | OAuth2 { .. } => {
+            loggedin(&profile_name, &hub, &local_info, &resource, &target).await?
+        }
     }
 
     Ok(())
@@ -1,9 +1,14 @@
+use crate::errors::NcsError;
 use crate::login;
+use crate::login::{LoginCancelToken, LoginOption, OAuth2Client, OAuth2Grant};
 use crate::setting::readwrite::save_client_hub_to_toml;
-use crate::setting::{ClientHub, LoginStatus};
+use crate::setting::{ClientHub, LocalInfo, LoginStatus, OAuth2Token};
+use crate::sync::{state_path, SyncState};
 use anyhow::Result;
+use futures_util::future::BoxFuture;
 use std::io::stdin;
 use std::io::{stdout, Write};
+use std::path::Path;
 
 pub async fn login_request(
     target_name: &str,
@@ -18,7 +23,7 @@ pub async fn login_request(
     stdin().read_line(&mut host).unwrap();
     let host = host.trim();
 
-    let res = login::login_request(&client.client_hub, host).await?;
+    let res = login::login_request(client.get_reqclient(), host).await?;
 
     println!("\nPlease log in your Next Cloud from:\n\n\t{}\n", res.login);
 
@@ -43,7 +48,7 @@ pub async fn poll(
         _ => return Err(anyhow::anyhow!("not polling")),
     };
 
-    let res = login::polling(client.client_hub, &token, &end_point).await?;
+    let res = login::polling(client.get_reqclient(), &token, &end_point).await?;
 
     let res = match res {
         Some(v) => v,
@@ -55,9 +60,302 @@ pub async fn poll(
         username: res.login_name,
         password: res.app_password,
     };
+    client.client_hub.clear_relogin_required(target_name)?;
+    client.client_hub.clear_cached_capabilities(target_name)?;
     save_client_hub_to_toml(&client.client_hub, client_hub_file_path)?;
 
     println!("You are logged in NextCloud.");
 
     Ok(())
 }
+
+pub async fn login(
+    target_name: &str,
+    client_hub: &mut ClientHub,
+    client_hub_file_path: &str,
+    host: &str,
+    option: &LoginOption,
+    cancel: Option<LoginCancelToken>,
+) -> Result<()> {
+    let mut client = client_hub.get_mut_client(target_name)?;
+
+    let issued_at = tokio::time::Instant::now();
+    let res = login::login_request(client.get_reqclient(), host).await?;
+
+    println!("\nPlease log in your Next Cloud from:\n\n\t{}\n", res.login);
+
+    if option.open_browser {
+        if let Err(e) = login::open_browser(&res.login) {
+            log::warn!("Could not open browser: {:?}", e);
+        }
+    }
+
+    // 失敗したら戻す。reauth なら host などを残しておく。前の Polling の token は使えない
+    let previous = match &client.get_profile().login_status {
+        LoginStatus::Polling { .. } => LoginStatus::NotYet,
+        status => status.clone(),
+    };
+    client.get_mut_profile().login_status = LoginStatus::Polling {
+        token: res.poll.token.clone(),
+        end_point: res.poll.endpoint.clone(),
+    };
+    save_client_hub_to_toml(client.client_hub, client_hub_file_path)?;
+
+    let res = login::polling_loop(
+        client.get_reqclient(),
+        &res.poll.token,
+        &res.poll.endpoint,
+        issued_at,
+        option.poll_interval,
+        cancel,
+    )
+    .await;
+
+    let res = match res {
+        Ok(v) => v,
+        Err(e) => {
+            // 期限切れやキャンセルで終わった token で Polling を続けても意味がない
+            client.get_mut_profile().login_status = previous;
+            save_client_hub_to_toml(client.client_hub, client_hub_file_path)?;
+            return Err(e);
+        }
+    };
+
+    client.get_mut_profile().login_status = LoginStatus::LoggedIn {
+        host: res.server,
+        username: res.login_name,
+        password: res.app_password,
+    };
+    client.client_hub.clear_relogin_required(target_name)?;
+    client.client_hub.clear_cached_capabilities(target_name)?;
+    save_client_hub_to_toml(client.client_hub, client_hub_file_path)?;
+
+    println!("You are logged in NextCloud.");
+
+    Ok(())
+}
+
+// ブラウザの無い環境向け。passwordは通常のものでもapp passwordでもよい
+pub async fn login_with_password(
+    target_name: &str,
+    client_hub: &mut ClientHub,
+    client_hub_file_path: &str,
+    host: &str,
+    username: &str,
+    password: &str,
+) -> Result<()> {
+    let mut client = client_hub.get_mut_client(target_name)?;
+
+    let app_password =
+        login::get_app_password(client.get_reqclient(), host, username, password).await?;
+    login::verify_app_password(client.get_reqclient(), host, username, &app_password).await?;
+
+    client.get_mut_profile().login_status = LoginStatus::LoggedIn {
+        host: host.to_string(),
+        username: username.to_string(),
+        password: app_password,
+    };
+    client.client_hub.clear_relogin_required(target_name)?;
+    client.client_hub.clear_cached_capabilities(target_name)?;
+    save_client_hub_to_toml(client.client_hub, client_hub_file_path)?;
+
+    println!("You are logged in NextCloud.");
+
+    Ok(())
+}
+
+// 認証情報が失効したprofileについて、前回と同じ方法・hostでログインをやり直す
+pub async fn reauth(
+    target_name: &str,
+    client_hub: &mut ClientHub,
+    client_hub_file_path: &str,
+    option: &LoginOption,
+    cancel: Option<LoginCancelToken>,
+) -> Result<()> {
+    let login_status = client_hub
+        .get_client(target_name)?
+        .get_profile()
+        .login_status
+        .clone();
+
+    match login_status {
+        LoginStatus::LoggedIn { host, .. } => {
+            login(
+                target_name,
+                client_hub,
+                client_hub_file_path,
+                &host,
+                option,
+                cancel,
+            )
+            .await
+        }
+        LoginStatus::OAuth2 {
+            host,
+            client_id,
+            client_secret,
+            redirect_uri,
+            ..
+        } => {
+            let oauth2_client = OAuth2Client {
+                client_id,
+                client_secret,
+                redirect_uri,
+            };
+            login_oauth2(
+                target_name,
+                client_hub,
+                client_hub_file_path,
+                &host,
+                &oauth2_client,
+            )
+            .await
+        }
+        _ => Err(NcsError::NotLoggedIn.into()),
+    }
+}
+
+// operation の途中で認証が切れたら、その profile にログインし直してから一度だけやり直す
+pub async fn retry_after_reauth<T, F>(
+    client_hub: &mut ClientHub,
+    client_hub_file_path: &str,
+    option: &LoginOption,
+    cancel: Option<LoginCancelToken>,
+    operation: F,
+) -> Result<T>
+where
+    F: for<'a> Fn(&'a ClientHub) -> BoxFuture<'a, Result<T>>,
+{
+    let mut relogin = client_hub.subscribe_relogin();
+
+    let res = operation(client_hub).await;
+    let mut profiles = Vec::new();
+    while let Ok(profile_name) = relogin.try_recv() {
+        profiles.push(profile_name);
+    }
+    if profiles.is_empty() {
+        return res;
+    }
+    profiles.sort();
+    profiles.dedup();
+
+    for profile_name in profiles.iter() {
+        println!("{}: please log in again.", profile_name);
+        reauth(
+            profile_name,
+            client_hub,
+            client_hub_file_path,
+            option,
+            cancel.clone(),
+        )
+        .await?;
+    }
+    operation(client_hub).await
+}
+
+pub async fn login_oauth2(
+    target_name: &str,
+    client_hub: &mut ClientHub,
+    client_hub_file_path: &str,
+    host: &str,
+    oauth2_client: &OAuth2Client,
+) -> Result<()> {
+    let mut client = client_hub.get_mut_client(target_name)?;
+
+    let url = login::oauth2_authorize_url(host, oauth2_client)?;
+    println!("\nPlease log in your Next Cloud from:\n\n\t{}\n", url);
+
+    let mut code = String::new();
+    print!("authorization code? : ");
+    stdout().flush().unwrap();
+    stdin().read_line(&mut code).unwrap();
+    let code = code.trim();
+
+    let res = login::oauth2_request_token(
+        client.get_reqclient(),
+        host,
+        oauth2_client,
+        OAuth2Grant::AuthorizationCode(code),
+    )
+    .await?;
+
+    client.get_mut_profile().login_status = LoginStatus::OAuth2 {
+        host: host.to_string(),
+        username: res.user_id,
+        client_id: oauth2_client.client_id.clone(),
+        client_secret: oauth2_client.client_secret.clone(),
+        redirect_uri: oauth2_client.redirect_uri.clone(),
+        token: OAuth2Token::new(res.access_token, res.refresh_token, res.expires_in),
+    };
+    client.client_hub.clear_oauth2_token(target_name)?;
+    client.client_hub.clear_relogin_required(target_name)?;
+    client.client_hub.clear_cached_capabilities(target_name)?;
+    save_client_hub_to_toml(client.client_hub, client_hub_file_path)?;
+
+    println!("You are logged in NextCloud.");
+
+    Ok(())
+}
+
+// 手元の認証情報と同期状態を先に消し、サーバー側の app password の失効はその後で行う。
+// state_dir は daemon に渡しているものと同じもの
+pub async fn logout(
+    target_name: &str,
+    client_hub: &mut ClientHub,
+    client_hub_file_path: &str,
+    local_info: &LocalInfo,
+    state_dir: Option<&Path>,
+) -> Result<()> {
+    // サーバーが失効させたのを確かめる (200 か 401) まで認証情報は消さない
+    let username = {
+        let client = client_hub.get_client(target_name)?;
+        let login_status = &client.get_profile().login_status;
+        if let LoginStatus::NotYet | LoginStatus::Polling { .. } = login_status {
+            println!("You are not logged in.");
+            return Ok(());
+        }
+        let request = login::delete_app_password_request(&client).await?;
+        login::delete_app_password(request).await?;
+        login_status.get_username().map(|u| u.to_string())
+    };
+
+    let deleted = match username {
+        Some(username) => client_hub
+            .get_credential_store()
+            .delete(target_name, &username),
+        None => Ok(()),
+    };
+
+    let mut client = client_hub.get_mut_client(target_name)?;
+    let profile = client.get_mut_profile();
+    profile.login_status = LoginStatus::NotYet;
+    profile.last_activity_id = None;
+    client.client_hub.clear_oauth2_token(target_name)?;
+    client.client_hub.clear_cached_capabilities(target_name)?;
+    client.client_hub.clear_relogin_required(target_name)?;
+    save_client_hub_to_toml(client.client_hub, client_hub_file_path)?;
+
+    // 次に別のアカウントでログインしても前の etag などを使わないようにする。
+    // state_dir に無いものは sync_once がアカウントの違いを見て捨てる
+    if let Some(state_dir) = state_dir {
+        for pair in local_info
+            .get_folder_pairs()
+            .iter()
+            .filter(|p| p.profile == target_name)
+        {
+            let path = state_path(state_dir, pair)?;
+            if !path.exists() {
+                continue;
+            }
+            let mut state = SyncState::load(&path).unwrap_or_default();
+            state.forget_remote();
+            state.save(&path)?;
+        }
+    }
+
+    deleted?;
+
+    println!("You are logged out.");
+
+    Ok(())
+}
@@ -9,9 +9,14 @@ use reqwest::Method;
 use std::path::Path;
 use std::time::Duration;
 use tokio::time::sleep;
-use urlencoding::decode;
 
+pub mod activity;
+pub mod capabilities;
+pub mod changes;
+pub mod delete;
 pub mod download;
+pub mod push;
+pub mod quota;
 pub mod upload;
 
 pub const WEBDAV_BODY: &str = r#"<?xml version="1.0"?>
@@ -38,7 +43,8 @@ async fn reqest(client: &Client<'_>, target: &Path) -> Result<Vec<Entry>> {
     let mut counter = 0;
     let res = loop {
         let res = client
-            .get_request_builder(Method::from_bytes(b"PROPFIND").unwrap(), url.clone())?
+            .get_request_builder(Method::from_bytes(b"PROPFIND").unwrap(), url.clone())
+            .await?
             .header("Depth", "Infinity")
             .body(WEBDAV_BODY)
             .send()
@@ -48,7 +54,12 @@ async fn reqest(client: &Client<'_>, target: &Path) -> Result<Vec<Entry>> {
         }
 
         if res.status().as_u16() == 401 {
-            return Err(NotAuthorized.into());
+            return Err(client.not_authorized());
+        }
+
+        // 存在しないものは何度聞いても存在しない
+        if res.status().as_u16() == 404 {
+            return Err(BadStatusError(404).into());
         }
 
         if counter >= 3 {
@@ -66,6 +77,24 @@ async fn reqest(client: &Client<'_>, target: &Path) -> Result<Vec<Entry>> {
     Ok(response)
 }
 
+// 401ならprofileに再ログインが必要な印を付ける
+pub(crate) fn check_response(
+    client: &Client<'_>,
+    res: reqwest::Response,
+) -> Result<reqwest::Response> {
+    let status = res.status();
+
+    if status.as_u16() == 401 {
+        return Err(client.not_authorized());
+    }
+
+    if !status.is_success() {
+        return Err(BadStatusError(status.as_u16()).into());
+    }
+
+    Ok(res)
+}
+
 async fn get(client: &Client<'_>, target: &Path) -> Result<Entry> {
     log::debug!("target: {:?}", target);
     let response = reqest(client, target).await?;
@@ -150,8 +179,7 @@ fn webdav_xml2responses(document: &roxmltree::Document, root_prefix: &str) -> Re
                 match m.tag_name().name() {
                     "href" => {
                         if let Some(href) = m.text() {
-                            let path = decode(&href)?;
-                            let path = url2path(&path, root_prefix)?;
+                            let path = url2path(href, root_prefix)?;
                             path_w = Some(path);
                         }
                     }
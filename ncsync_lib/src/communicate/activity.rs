@@ -0,0 +1,291 @@
+use crate::communicate::check_response;
+use crate::errors::NcsError::*;
+use crate::setting::readwrite::save_client_hub_to_toml;
+use crate::setting::{Client, ClientHub, OCS_ROOT};
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use reqwest::Method;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+const DEFAULT_LIMIT: u32 = 50;
+
+#[derive(Debug, Clone)]
+pub enum ActivityFilter {
+    All,
+    // 自分の操作のみ
+    Mine,
+    // 他のユーザーの操作のみ
+    ByOthers,
+    Files,
+    Object { object_type: String, object_id: u64 },
+}
+
+impl ActivityFilter {
+    fn path(&self) -> &str {
+        match self {
+            ActivityFilter::All => "all",
+            ActivityFilter::Mine => "self",
+            ActivityFilter::ByOthers => "by",
+            ActivityFilter::Files => "files",
+            ActivityFilter::Object { .. } => "filter",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ActivityQuery {
+    pub filter: ActivityFilter,
+    // このactivity_idより後のものを取得する
+    pub since: Option<u64>,
+    pub limit: u32,
+    // trueなら新しい順
+    pub descending: bool,
+}
+
+impl ActivityQuery {
+    pub fn new(filter: ActivityFilter, since: Option<u64>) -> Self {
+        Self {
+            filter,
+            since,
+            limit: DEFAULT_LIMIT,
+            descending: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Activity {
+    pub activity_id: u64,
+    pub app: String,
+    pub activity_type: String,
+    pub user: String,
+    pub subject: String,
+    pub message: String,
+    pub object_type: String,
+    pub object_id: u64,
+    pub object_name: String,
+    // object_id -> path (ユーザーのルートからの絶対パス)
+    pub objects: HashMap<u64, PathBuf>,
+    // 移動・名前変更のときの移動元
+    pub old_path: Option<PathBuf>,
+    pub datetime: DateTime<Local>,
+}
+
+#[derive(Debug)]
+pub struct ActivityPage {
+    pub activities: Vec<Activity>,
+    // 次のページを取得するときの since
+    pub last_given: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OcsResponseJson {
+    ocs: OcsJson,
+}
+
+#[derive(Debug, Deserialize)]
+struct OcsJson {
+    data: Vec<ActivityJson>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ActivityJson {
+    activity_id: u64,
+    #[serde(default)]
+    app: String,
+    #[serde(rename = "type", default)]
+    activity_type: String,
+    #[serde(default)]
+    user: String,
+    #[serde(default)]
+    subject: String,
+    #[serde(default)]
+    message: String,
+    #[serde(default)]
+    object_type: String,
+    #[serde(default)]
+    object_id: u64,
+    #[serde(default)]
+    object_name: String,
+    // 空のときはPHPの都合で [] になる
+    #[serde(default)]
+    objects: serde_json::Value,
+    // [subject, {param名: {..., path}}]
+    #[serde(default)]
+    subject_rich: serde_json::Value,
+    datetime: String,
+}
+
+impl ActivityJson {
+    fn to(self) -> Result<Activity> {
+        let objects = match self.objects {
+            serde_json::Value::Object(map) => map
+                .into_iter()
+                .filter_map(|(id, path)| {
+                    let id = id.parse::<u64>().ok()?;
+                    let path = path.as_str()?;
+                    Some((id, PathBuf::from(path)))
+                })
+                .collect(),
+            _ => HashMap::new(),
+        };
+        let old_path = self
+            .subject_rich
+            .get(1)
+            .and_then(|params| params.get("oldfile"))
+            .and_then(|oldfile| oldfile.get("path"))
+            .and_then(|path| path.as_str())
+            .map(|path| PathBuf::from(format!("/{}", path.trim_start_matches('/'))));
+        let datetime = DateTime::parse_from_rfc3339(&self.datetime)?.into();
+
+        Ok(Activity {
+            activity_id: self.activity_id,
+            app: self.app,
+            activity_type: self.activity_type,
+            user: self.user,
+            subject: self.subject,
+            message: self.message,
+            object_type: self.object_type,
+            object_id: self.object_id,
+            object_name: self.object_name,
+            objects,
+            old_path,
+            datetime,
+        })
+    }
+}
+
+async fn fetch_activities(client: &Client<'_>, query: &ActivityQuery) -> Result<ActivityPage> {
+    let host = client.get_host()?.ok_or(NotLoggedIn)?;
+    let mut url = host.join(&format!("{}/{}", OCS_ROOT, query.filter.path()))?;
+    {
+        let mut pairs = url.query_pairs_mut();
+        pairs
+            .append_pair("format", "json")
+            .append_pair("sort", if query.descending { "desc" } else { "asc" })
+            .append_pair("limit", &query.limit.to_string());
+        if let Some(since) = query.since {
+            pairs.append_pair("since", &since.to_string());
+        }
+        if let ActivityFilter::Object {
+            object_type,
+            object_id,
+        } = &query.filter
+        {
+            pairs
+                .append_pair("object_type", object_type)
+                .append_pair("object_id", &object_id.to_string());
+        }
+    }
+
+    let res = client
+        .get_request_builder(Method::GET, url)
+        .await?
+        .send()
+        .await?;
+
+    // 304 はこれ以上新しいactivityが無いことを表す
+    if res.status().as_u16() == 304 {
+        return Ok(ActivityPage {
+            activities: Vec::new(),
+            last_given: None,
+        });
+    }
+
+    let res = check_response(client, res)?;
+    let last_given = res
+        .headers()
+        .get("X-Activity-Last-Given")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    let json: OcsResponseJson = res.json().await?;
+    let activities = json
+        .ocs
+        .data
+        .into_iter()
+        .map(|a| a.to())
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(ActivityPage {
+        activities,
+        last_given,
+    })
+}
+
+pub async fn activities(
+    profile_name: &str,
+    client_hub: &ClientHub,
+    query: &ActivityQuery,
+) -> Result<ActivityPage> {
+    let client = client_hub.get_client(profile_name)?;
+
+    fetch_activities(&client, query).await
+}
+
+// since 以降のactivityを全ページ分取得する
+pub async fn activities_since(
+    profile_name: &str,
+    client_hub: &ClientHub,
+    filter: ActivityFilter,
+    since: Option<u64>,
+) -> Result<Vec<Activity>> {
+    let client = client_hub.get_client(profile_name)?;
+    let mut query = ActivityQuery::new(filter, since);
+    let mut res = Vec::new();
+
+    loop {
+        let page = fetch_activities(&client, &query).await?;
+        let done = page.activities.is_empty();
+        let last = page
+            .last_given
+            .or_else(|| page.activities.last().map(|a| a.activity_id));
+        res.extend(page.activities);
+
+        match last {
+            Some(last) if !done && Some(last) != query.since => query.since = Some(last),
+            _ => break,
+        }
+    }
+
+    Ok(res)
+}
+
+pub async fn latest_activity_id(
+    profile_name: &str,
+    client_hub: &ClientHub,
+    filter: ActivityFilter,
+) -> Result<Option<u64>> {
+    let query = ActivityQuery {
+        limit: 1,
+        descending: true,
+        ..ActivityQuery::new(filter, None)
+    };
+    let page = activities(profile_name, client_hub, &query).await?;
+
+    Ok(page.activities.first().map(|a| a.activity_id))
+}
+
+// 前回見たactivity以降のものを取得し、最後のactivity_idをprofiles.tomlに保存する
+pub async fn new_activities(
+    profile_name: &str,
+    client_hub: &mut ClientHub,
+    client_hub_file_path: &str,
+    filter: ActivityFilter,
+) -> Result<Vec<Activity>> {
+    let since = client_hub
+        .get_profile(profile_name)
+        .ok_or_else(|| ProfileNotFound(profile_name.to_string()))?
+        .last_activity_id;
+
+    let activities = activities_since(profile_name, client_hub, filter, since).await?;
+
+    if let Some(last) = activities.iter().map(|a| a.activity_id).max() {
+        let mut client = client_hub.get_mut_client(profile_name)?;
+        client.get_mut_profile().last_activity_id = Some(last);
+        save_client_hub_to_toml(client.client_hub, client_hub_file_path)?;
+    }
+
+    Ok(activities)
+}
@@ -0,0 +1,225 @@
+use crate::communicate::check_response;
+use crate::errors::NcsError::*;
+use crate::setting::{Client, ClientHub};
+use anyhow::Result;
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+
+const CAPABILITIESURL: &str = "/ocs/v1.php/cloud/capabilities?format=json";
+const STATUSURL: &str = "/status.php";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ServerVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub micro: u32,
+    pub string: String,
+    pub edition: String,
+}
+
+impl ServerVersion {
+    pub fn at_least(&self, major: u32, minor: u32, micro: u32) -> bool {
+        (self.major, self.minor, self.micro) >= (major, minor, micro)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DavCapabilities {
+    // "1.0" なら chunked upload v1, "NG" などもありうる
+    pub chunking: Option<String>,
+    pub bulkupload: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FilesCapabilities {
+    pub bigfilechunking: bool,
+    pub undelete: bool,
+    pub versioning: bool,
+    pub blacklisted_files: Vec<String>,
+    // Nextcloud 30 から。古いサーバーでは空
+    pub forbidden_filenames: Vec<String>,
+    pub forbidden_filename_basenames: Vec<String>,
+    pub forbidden_filename_characters: Vec<String>,
+    pub forbidden_filename_extensions: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SharingPublicCapabilities {
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SharingCapabilities {
+    pub api_enabled: bool,
+    pub public: SharingPublicCapabilities,
+    pub resharing: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NotifyPushEndpoints {
+    pub websocket: String,
+    pub pre_auth: String,
+}
+
+// notify_push アプリが入っているときだけ存在する
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NotifyPushCapabilities {
+    // "files", "activities", "notifications" など
+    #[serde(rename = "type")]
+    pub types: Vec<String>,
+    pub endpoints: NotifyPushEndpoints,
+}
+
+impl NotifyPushCapabilities {
+    pub fn supports_files(&self) -> bool {
+        !self.endpoints.websocket.is_empty() && self.types.iter().any(|t| t == "files")
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerStatus {
+    pub installed: bool,
+    pub maintenance: bool,
+    #[serde(rename = "needsDbUpgrade")]
+    pub needs_db_upgrade: bool,
+    pub versionstring: String,
+    pub productname: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Capabilities {
+    pub version: ServerVersion,
+    pub status: ServerStatus,
+    pub dav: DavCapabilities,
+    pub files: FilesCapabilities,
+    pub sharing: SharingCapabilities,
+    pub notify_push: Option<NotifyPushCapabilities>,
+}
+
+impl Capabilities {
+    pub fn supports_chunking(&self) -> bool {
+        self.dav.chunking.is_some() || self.files.bigfilechunking
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OcsResponseJson {
+    ocs: OcsJson,
+}
+
+#[derive(Debug, Deserialize)]
+struct OcsJson {
+    data: CapabilitiesDataJson,
+}
+
+#[derive(Debug, Deserialize)]
+struct CapabilitiesDataJson {
+    version: ServerVersion,
+    capabilities: CapabilitiesJson,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct CapabilitiesJson {
+    dav: DavCapabilities,
+    files: FilesCapabilities,
+    files_sharing: SharingCapabilities,
+    notify_push: Option<NotifyPushCapabilities>,
+}
+
+async fn fetch_status(client: &Client<'_>) -> Result<ServerStatus> {
+    let host = client.get_host()?.ok_or(NotLoggedIn)?;
+    let url = host.join(STATUSURL)?;
+    // status.php は認証不要
+    let res = client.get_reqclient().get(url).send().await?;
+    let res = check_response(client, res)?;
+
+    Ok(res.json().await?)
+}
+
+async fn fetch_capabilities(client: &Client<'_>) -> Result<Capabilities> {
+    let host = client.get_host()?.ok_or(NotLoggedIn)?;
+    let url = host.join(CAPABILITIESURL)?;
+    let res = client
+        .get_request_builder(Method::GET, url)
+        .await?
+        .send()
+        .await?;
+    let res = check_response(client, res)?;
+    let json: OcsResponseJson = res.json().await?;
+
+    let status = fetch_status(client).await?;
+
+    let data = json.ocs.data;
+    Ok(Capabilities {
+        version: data.version,
+        status,
+        dav: data.capabilities.dav,
+        files: data.capabilities.files,
+        sharing: data.capabilities.files_sharing,
+        notify_push: data.capabilities.notify_push,
+    })
+}
+
+// profileごとにキャッシュする
+pub async fn capabilities(profile_name: &str, client_hub: &ClientHub) -> Result<Capabilities> {
+    if let Some(capabilities) = client_hub.get_cached_capabilities(profile_name) {
+        return Ok(capabilities);
+    }
+
+    refresh_capabilities(profile_name, client_hub).await
+}
+
+pub async fn refresh_capabilities(
+    profile_name: &str,
+    client_hub: &ClientHub,
+) -> Result<Capabilities> {
+    let client = client_hub.get_client(profile_name)?;
+    let capabilities = fetch_capabilities(&client).await?;
+    client_hub.set_cached_capabilities(profile_name, capabilities.clone())?;
+
+    Ok(capabilities)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capabilities_json_test() {
+        let json = r#"{"ocs":{"meta":{"status":"ok","statuscode":100},"data":{
+            "version":{"major":25,"minor":0,"micro":2,"string":"25.0.2","edition":"","extendedSupport":false},
+            "capabilities":{
+                "core":{"pollinterval":60},
+                "dav":{"chunking":"1.0","bulkupload":"1.0"},
+                "files":{"bigfilechunking":true,"blacklisted_files":[".htaccess"],"undelete":true,
+                    "forbidden_filename_characters":["\\","*"],"forbidden_filename_extensions":[" ",".",".filepart"]},
+                "files_sharing":{"api_enabled":true,"public":{"enabled":false,"password":{"enforced":false}}},
+                "notify_push":{"type":["files","activities","notifications"],"endpoints":{
+                    "websocket":"wss://cloud.example.com/push/ws",
+                    "pre_auth":"https://cloud.example.com/apps/notify_push/pre_auth"}}
+            }}}}"#;
+        let json: OcsResponseJson = serde_json::from_str(json).unwrap();
+        let data = json.ocs.data;
+
+        assert!(data.version.at_least(25, 0, 0));
+        assert!(!data.version.at_least(25, 0, 3));
+        assert_eq!(data.capabilities.dav.chunking.as_deref(), Some("1.0"));
+        assert_eq!(data.capabilities.files.blacklisted_files, vec![".htaccess"]);
+        assert!(!data.capabilities.files.versioning);
+        assert_eq!(
+            data.capabilities.files.forbidden_filename_characters,
+            vec!["\\", "*"]
+        );
+        assert!(data.capabilities.files_sharing.api_enabled);
+        assert!(!data.capabilities.files_sharing.public.enabled);
+        assert!(data.capabilities.notify_push.unwrap().supports_files());
+    }
+}
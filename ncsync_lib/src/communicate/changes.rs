@@ -0,0 +1,291 @@
+use crate::communicate::activity::{self, Activity, ActivityFilter};
+use crate::communicate::{get, ls, ls_rec};
+use crate::entry::{Entry, EntryType};
+use crate::errors::NcsError::*;
+use crate::setting::readwrite::save_client_hub_to_toml;
+use crate::setting::{Client, ClientHub};
+use anyhow::Result;
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+
+// 変更されたパスがこれより多ければ個別にPROPFINDするより全体を取り直したほうが早い
+const MAX_INCREMENTAL_PATHS: usize = 200;
+
+#[derive(Debug)]
+pub enum RemoteChanges {
+    // activity feed から求めた変更。changed には作成・変更・移動先が入る
+    Incremental {
+        changed: Vec<Entry>,
+        deleted: Vec<PathBuf>,
+    },
+    // 全体を取り直して前回のツリーとETagで比較した結果
+    Full {
+        root: Entry,
+        changed: Vec<PathBuf>,
+        deleted: Vec<PathBuf>,
+    },
+}
+
+fn is_under(path: &Path, root: &Path) -> bool {
+    path.starts_with(root)
+}
+
+// activity から影響を受けたパスを集める
+fn affected_paths(activities: &[Activity], root: &Path) -> BTreeSet<PathBuf> {
+    activities
+        .iter()
+        .filter(|a| a.object_type == "files")
+        .flat_map(|a| a.objects.values().cloned().chain(a.old_path.clone()))
+        .filter(|p| is_under(p, root))
+        .collect()
+}
+
+async fn stat(client: &Client<'_>, path: &Path) -> Result<Option<Entry>> {
+    match get(client, path).await {
+        Ok(mut entry) => {
+            ls_rec(client, &mut entry).await?;
+            Ok(Some(entry))
+        }
+        Err(e) => match e.downcast_ref() {
+            Some(BadStatusError(404)) => Ok(None),
+            _ => Err(e),
+        },
+    }
+}
+
+fn collect_etags<'a>(entry: &'a Entry, res: &mut HashMap<&'a Path, Option<String>>) {
+    match &entry.entry_type {
+        EntryType::File { etag } => {
+            res.insert(&entry.path, etag.as_ref().map(|e| e.get().to_string()));
+        }
+        EntryType::Dir { children } => {
+            res.insert(&entry.path, None);
+            for c in children.values() {
+                collect_etags(c, res);
+            }
+        }
+    }
+}
+
+// 前回のツリーとの差分をETagで求める
+fn diff_trees(previous: Option<&Entry>, current: &Entry) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    let mut old = HashMap::new();
+    if let Some(previous) = previous {
+        collect_etags(previous, &mut old);
+    }
+    let mut new = HashMap::new();
+    collect_etags(current, &mut new);
+
+    let mut changed = new
+        .iter()
+        .filter(|(p, etag)| match old.get(*p) {
+            Some(old_etag) => old_etag != *etag,
+            None => true,
+        })
+        .map(|(p, _)| p.to_path_buf())
+        .collect::<Vec<_>>();
+    let mut deleted = old
+        .keys()
+        .filter(|p| !new.contains_key(*p))
+        .map(|p| p.to_path_buf())
+        .collect::<Vec<_>>();
+    changed.sort();
+    deleted.sort();
+
+    (changed, deleted)
+}
+
+async fn full_crawl(
+    profile_name: &str,
+    client_hub: &ClientHub,
+    root: &str,
+    previous: Option<&Entry>,
+) -> Result<RemoteChanges> {
+    let current = ls(profile_name, client_hub, root).await?;
+    let (changed, deleted) = diff_trees(previous, &current);
+
+    Ok(RemoteChanges::Full {
+        root: current,
+        changed,
+        deleted,
+    })
+}
+
+async fn incremental(
+    profile_name: &str,
+    client_hub: &ClientHub,
+    root: &Path,
+    activities: &[Activity],
+) -> Result<Option<RemoteChanges>> {
+    let paths = affected_paths(activities, root);
+    if paths.len() > MAX_INCREMENTAL_PATHS {
+        return Ok(None);
+    }
+
+    let client = client_hub.get_client(profile_name)?;
+    let mut changed: Vec<Entry> = Vec::new();
+    let mut deleted = Vec::new();
+    for path in paths {
+        // 親ディレクトリを取得済みなら子孫も含まれている
+        if changed
+            .iter()
+            .any(|e| e.is_dir() && path.starts_with(&e.path))
+        {
+            continue;
+        }
+        match stat(&client, &path).await? {
+            Some(entry) => changed.push(entry),
+            None => deleted.push(path),
+        }
+    }
+
+    Ok(Some(RemoteChanges::Incremental { changed, deleted }))
+}
+
+// since の activity が期限切れなどで消えていると 403 になる。404 は activity アプリが無くなったとき
+fn is_stale_cursor(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref(),
+        Some(BadStatusError(403)) | Some(BadStatusError(404))
+    )
+}
+
+// 304 で何も返らなくても、サーバーにある最新の activity より先を指していれば続けられない
+fn is_beyond_latest(since: u64, latest: Option<u64>) -> bool {
+    match latest {
+        Some(latest) => since > latest,
+        None => true,
+    }
+}
+
+// since から activity を取得する。since が使えなければ None
+async fn activities_from_cursor(
+    profile_name: &str,
+    client_hub: &ClientHub,
+    since: u64,
+) -> Result<Option<Vec<Activity>>> {
+    let activities = match activity::activities_since(
+        profile_name,
+        client_hub,
+        ActivityFilter::Files,
+        Some(since),
+    )
+    .await
+    {
+        Ok(activities) => activities,
+        Err(e) if is_stale_cursor(&e) => {
+            log::warn!("activity cursor {} is no longer valid: {:?}", since, e);
+            return Ok(None);
+        }
+        Err(e) => return Err(e),
+    };
+
+    if activities.is_empty() {
+        let latest =
+            activity::latest_activity_id(profile_name, client_hub, ActivityFilter::Files).await?;
+        if is_beyond_latest(since, latest) {
+            log::warn!(
+                "activity cursor {} is ahead of the latest activity {:?}",
+                since,
+                latest
+            );
+            return Ok(None);
+        }
+    }
+
+    Ok(Some(activities))
+}
+
+// since 以降に root 以下で起きた変更と、次回に使うactivity_idを返す。
+// since が無い・使えなくなっている・変更が多すぎるときはETagによる全体比較にする。
+// それ以外のエラーでは全体を取り直さずにエラーを返す
+pub async fn remote_changes_since(
+    profile_name: &str,
+    client_hub: &ClientHub,
+    root: &str,
+    previous: Option<&Entry>,
+    since: Option<u64>,
+) -> Result<(RemoteChanges, Option<u64>)> {
+    let fetched = match since {
+        Some(since) => activities_from_cursor(profile_name, client_hub, since).await?,
+        None => None,
+    };
+
+    match fetched {
+        Some(activities) => {
+            let last = activities.iter().map(|a| a.activity_id).max().or(since);
+            match incremental(profile_name, client_hub, Path::new(root), &activities).await? {
+                Some(changes) => Ok((changes, last)),
+                None => Ok((
+                    full_crawl(profile_name, client_hub, root, previous).await?,
+                    last,
+                )),
+            }
+        }
+        None => {
+            // 次回から差分で取れるよう、取り直す前に最新のactivity_idを控えておく
+            let last =
+                activity::latest_activity_id(profile_name, client_hub, ActivityFilter::Files)
+                    .await
+                    .unwrap_or_else(|e| {
+                        log::warn!("activity feed is unavailable: {:?}", e);
+                        None
+                    });
+            Ok((
+                full_crawl(profile_name, client_hub, root, previous).await?,
+                last,
+            ))
+        }
+    }
+}
+
+// profileに保存したactivity_id以降の変更を求め、activity_idを進めて保存する
+pub async fn remote_changes(
+    profile_name: &str,
+    client_hub: &mut ClientHub,
+    client_hub_file_path: &str,
+    root: &str,
+    previous: Option<&Entry>,
+) -> Result<RemoteChanges> {
+    let since = client_hub
+        .get_profile(profile_name)
+        .ok_or_else(|| ProfileNotFound(profile_name.to_string()))?
+        .last_activity_id;
+
+    // 比較する元のツリーが無ければ差分は使えない
+    let (changes, last) = remote_changes_since(
+        profile_name,
+        client_hub,
+        root,
+        previous,
+        since.filter(|_| previous.is_some()),
+    )
+    .await?;
+
+    if last.is_some() && last != since {
+        let mut client = client_hub.get_mut_client(profile_name)?;
+        client.get_mut_profile().last_activity_id = last;
+        save_client_hub_to_toml(client.client_hub, client_hub_file_path)?;
+    }
+
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale_cursor_test() {
+        assert!(is_stale_cursor(&BadStatusError(403).into()));
+        assert!(is_stale_cursor(&BadStatusError(404).into()));
+        // 通信の失敗などでは全体を取り直さない
+        assert!(!is_stale_cursor(&BadStatusError(500).into()));
+        assert!(!is_stale_cursor(&NotAuthorized.into()));
+
+        assert!(!is_beyond_latest(10, Some(10)));
+        assert!(!is_beyond_latest(5, Some(10)));
+        assert!(is_beyond_latest(11, Some(10)));
+        assert!(is_beyond_latest(11, None));
+    }
+}
@@ -0,0 +1,29 @@
+use crate::communicate::check_response;
+use crate::path::AsNCUrl;
+use crate::setting::ClientHub;
+use anyhow::Result;
+use reqwest::Method;
+use std::path::Path;
+
+// 既に存在しない (404) 場合も成功とみなす
+pub async fn delete(
+    profile_name: &str,
+    client_hub: &ClientHub,
+    path: impl AsRef<Path>,
+) -> Result<()> {
+    let client = &client_hub.get_client(profile_name)?;
+
+    let url = path.as_ref().as_nc_url(client)?;
+    let res = client
+        .get_request_builder(Method::DELETE, url)
+        .await?
+        .send()
+        .await?;
+
+    if res.status().as_u16() == 404 {
+        return Ok(());
+    }
+    check_response(client, res)?;
+
+    Ok(())
+}
@@ -1,4 +1,4 @@
-use crate::communicate::get;
+use crate::communicate::{check_response, get};
 use crate::path::AsNCUrl;
 use crate::setting::ClientHub;
 use anyhow::Result;
@@ -20,7 +20,12 @@ pub async fn download(
     }
 
     let url = path.as_ref().as_nc_url(client)?;
-    let res = client.get_request_builder(Method::GET, url)?.send().await?;
+    let res = client
+        .get_request_builder(Method::GET, url)
+        .await?
+        .send()
+        .await?;
+    let res = check_response(client, res)?;
 
     Ok(res.bytes().await?)
 }
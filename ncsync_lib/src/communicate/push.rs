@@ -0,0 +1,240 @@
+use crate::communicate::capabilities::{capabilities, NotifyPushEndpoints};
+use crate::communicate::check_response;
+use crate::errors::NcsError::*;
+use crate::setting::{Client, ClientHub};
+use anyhow::Result;
+use futures_util::{SinkExt, StreamExt};
+use reqwest::{Method, Url};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::Connector;
+
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(60);
+const DEFAULT_MAX_RECONNECT_DELAY: Duration = Duration::from_secs(300);
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PushEvent {
+    // リモートのファイルが変わった。差分同期のきっかけにする
+    FilesChanged,
+    Activity,
+    Notification,
+    // notify_push が使えない・切断中のときに poll_interval ごとに送る
+    Poll,
+}
+
+#[derive(Debug, Clone)]
+pub struct PushOption {
+    pub poll_interval: Duration,
+    pub max_reconnect_delay: Duration,
+}
+
+impl Default for PushOption {
+    fn default() -> Self {
+        Self {
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            max_reconnect_delay: DEFAULT_MAX_RECONNECT_DELAY,
+        }
+    }
+}
+
+fn parse_message(text: &str) -> Option<PushEvent> {
+    // "notify_file_id [1,2]" のように引数が付くこともある
+    match text.split_whitespace().next()? {
+        "notify_file" | "notify_file_id" => Some(PushEvent::FilesChanged),
+        "notify_activity" => Some(PushEvent::Activity),
+        "notify_notification" => Some(PushEvent::Notification),
+        _ => None,
+    }
+}
+
+// app password や OAuth2 の token をwebsocketに流さずに済むよう、一時tokenを発行してもらう
+async fn pre_auth(client: &Client<'_>, endpoints: &NotifyPushEndpoints) -> Result<String> {
+    let url = Url::parse(&endpoints.pre_auth)?;
+    let res = client
+        .get_request_builder(Method::POST, url)
+        .await?
+        .send()
+        .await?;
+    let res = check_response(client, res)?;
+
+    Ok(res.text().await?)
+}
+
+enum Disconnected {
+    // 受け取り側がいなくなった
+    Stopped,
+    // 認証まで済んでから切れた
+    Lost,
+}
+
+async fn connect(
+    client: &Client<'_>,
+    endpoints: &NotifyPushEndpoints,
+    sender: &mpsc::Sender<PushEvent>,
+) -> Result<Disconnected> {
+    let token = pre_auth(client, endpoints).await?;
+    // CA 証明書やクライアント証明書は profile の設定に従う
+    let connector = client
+        .get_profile()
+        .get_network_setting()
+        .tls
+        .native_tls_connector()?;
+    let (mut ws, _) = tokio_tungstenite::connect_async_tls_with_config(
+        endpoints.websocket.as_str(),
+        None,
+        Some(Connector::NativeTls(connector)),
+    )
+    .await?;
+
+    // pre_auth のtokenを使うときは username を空にする
+    ws.send(Message::Text(String::new())).await?;
+    ws.send(Message::Text(token)).await?;
+
+    match ws.next().await {
+        Some(Ok(Message::Text(text))) if text == "authenticated" => (),
+        Some(Ok(Message::Text(text))) => {
+            return Err(anyhow::anyhow!("notify_push rejected: {}", text))
+        }
+        Some(Err(e)) => return Err(e.into()),
+        _ => return Err(anyhow::anyhow!("notify_push closed before authentication")),
+    }
+    log::info!("connected to notify_push: {}", endpoints.websocket);
+
+    // 切断中の変更を取りこぼさないよう、繋がったら一度同期させる
+    if sender.send(PushEvent::FilesChanged).await.is_err() {
+        return Ok(Disconnected::Stopped);
+    }
+
+    loop {
+        let message = tokio::select! {
+            message = ws.next() => message,
+            _ = sender.closed() => return Ok(Disconnected::Stopped),
+        };
+        let event = match message {
+            Some(Ok(Message::Text(text))) => parse_message(&text),
+            Some(Ok(Message::Close(_))) | None => return Ok(Disconnected::Lost),
+            Some(Ok(_)) => None,
+            Some(Err(e)) => {
+                log::warn!("notify_push: {:?}", e);
+                return Ok(Disconnected::Lost);
+            }
+        };
+        if let Some(event) = event {
+            if sender.send(event).await.is_err() {
+                return Ok(Disconnected::Stopped);
+            }
+        }
+    }
+}
+
+// 待っている間も poll_interval ごとに Poll を送る。受け取り側がいなくなれば false
+async fn wait_polling(
+    sender: &mpsc::Sender<PushEvent>,
+    duration: Duration,
+    poll_interval: Duration,
+) -> bool {
+    let mut rest = duration;
+    loop {
+        let step = rest.min(poll_interval);
+        tokio::select! {
+            _ = sleep(step) => (),
+            _ = sender.closed() => return false,
+        }
+        rest -= step;
+        if step == poll_interval && sender.send(PushEvent::Poll).await.is_err() {
+            return false;
+        }
+        if rest.is_zero() {
+            return true;
+        }
+    }
+}
+
+// notify_push からの変更通知を sender に流し続ける。
+// サーバーが notify_push に対応していなければ poll_interval ごとの Poll にする。
+// websocket は proxy を通せないので、proxy を設定した profile も Poll にする。
+// Receiver が drop されたら Ok(()) で終わり、認証が失効したときは NotAuthorized を返す
+pub async fn listen(
+    profile_name: &str,
+    client_hub: &ClientHub,
+    option: &PushOption,
+    sender: mpsc::Sender<PushEvent>,
+) -> Result<()> {
+    let client = client_hub.get_client(profile_name)?;
+    let mut delay = MIN_RECONNECT_DELAY;
+
+    if client.get_profile().get_network_setting().proxy.is_some() {
+        log::info!("{}: notify_push is not used through a proxy", profile_name);
+        while wait_polling(&sender, option.poll_interval, option.poll_interval).await {}
+        return Ok(());
+    }
+
+    loop {
+        let notify_push = match capabilities(profile_name, client_hub).await {
+            Ok(capabilities) => capabilities.notify_push.filter(|n| n.supports_files()),
+            Err(e) => match e.downcast_ref() {
+                Some(NotAuthorized) => return Err(e),
+                _ => {
+                    log::warn!("could not fetch capabilities: {:?}", e);
+                    None
+                }
+            },
+        };
+
+        let notify_push = match notify_push {
+            Some(notify_push) => notify_push,
+            None => {
+                if !wait_polling(&sender, option.poll_interval, option.poll_interval).await {
+                    return Ok(());
+                }
+                continue;
+            }
+        };
+
+        match connect(&client, &notify_push.endpoints, &sender).await {
+            Ok(Disconnected::Stopped) => return Ok(()),
+            Ok(Disconnected::Lost) => {
+                log::warn!("disconnected from notify_push");
+                delay = MIN_RECONNECT_DELAY;
+            }
+            Err(e) => match e.downcast_ref() {
+                Some(NotAuthorized) => return Err(e),
+                _ => log::warn!("could not connect to notify_push: {:?}", e),
+            },
+        }
+
+        // 繋がらない間は Poll で代わりに同期させる
+        if sender.send(PushEvent::Poll).await.is_err()
+            || !wait_polling(&sender, delay, option.poll_interval).await
+        {
+            return Ok(());
+        }
+        delay = (delay * 2).min(option.max_reconnect_delay);
+        // endpoint が変わっているかもしれないので取り直す
+        client_hub.clear_cached_capabilities(profile_name)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_message_test() {
+        assert_eq!(parse_message("notify_file"), Some(PushEvent::FilesChanged));
+        assert_eq!(
+            parse_message("notify_file_id [1,2]"),
+            Some(PushEvent::FilesChanged)
+        );
+        assert_eq!(parse_message("notify_activity"), Some(PushEvent::Activity));
+        assert_eq!(
+            parse_message("notify_notification"),
+            Some(PushEvent::Notification)
+        );
+        assert_eq!(parse_message("authenticated"), None);
+        assert_eq!(parse_message(""), None);
+    }
+}
@@ -0,0 +1,149 @@
+use crate::communicate::check_response;
+use crate::errors::NcsError::*;
+use crate::path::{check_absolute, AsNCUrl};
+use crate::setting::{Client, ClientHub};
+use anyhow::Result;
+use reqwest::Method;
+use std::path::Path;
+
+const QUOTA_BODY: &str = r#"<?xml version="1.0"?>
+<d:propfind  xmlns:d="DAV:">
+  <d:prop>
+        <d:quota-available-bytes />
+        <d:quota-used-bytes />
+  </d:prop>
+</d:propfind>
+"#;
+
+#[derive(Debug, Clone)]
+pub struct Quota {
+    pub used: u64,
+    // None は無制限 (もしくはサーバーが計算できなかった)
+    pub available: Option<u64>,
+}
+
+impl Quota {
+    pub fn can_store(&self, size: u64) -> bool {
+        match self.available {
+            Some(available) => size <= available,
+            None => true,
+        }
+    }
+}
+
+async fn fetch_quota(client: &Client<'_>, target: &Path) -> Result<Quota> {
+    if !check_absolute(target) {
+        return Err(BadPath.into());
+    }
+
+    let url = target.as_nc_url(client)?;
+    let res = client
+        .get_request_builder(Method::from_bytes(b"PROPFIND").unwrap(), url)
+        .await?
+        .header("Depth", "0")
+        .body(QUOTA_BODY)
+        .send()
+        .await?;
+    let res = check_response(client, res)?;
+
+    let text = res.text_with_charset("utf-8").await?;
+    let document = roxmltree::Document::parse(&text)?;
+
+    webdav_xml2quota(&document)
+}
+
+// 共有フォルダや外部ストレージでは 404 の propstat で値が返らないので、無制限として扱う
+fn webdav_xml2quota(document: &roxmltree::Document) -> Result<Quota> {
+    let mut found = false;
+    let mut used = None;
+    let mut available = None;
+
+    for d in document.root_element().descendants() {
+        match d.tag_name().name() {
+            "quota-used-bytes" => {
+                found = true;
+                used = used.or_else(|| d.text().and_then(|s| s.trim().parse::<u64>().ok()));
+            }
+            "quota-available-bytes" => {
+                found = true;
+                // 負の値 (-1: 未計算, -2: 不明, -3: 無制限) は制限なしとして扱う
+                available = available.or_else(|| {
+                    d.text()
+                        .and_then(|s| s.trim().parse::<i64>().ok())
+                        .filter(|n| *n >= 0)
+                        .map(|n| n as u64)
+                });
+            }
+            _ => (),
+        }
+    }
+
+    if !found {
+        return Err(InvalidXMLError.into());
+    }
+
+    Ok(Quota {
+        used: used.unwrap_or(0),
+        available,
+    })
+}
+
+pub async fn quota(
+    profile_name: &str,
+    client_hub: &ClientHub,
+    target: impl AsRef<Path>,
+) -> Result<Quota> {
+    let client = client_hub.get_client(profile_name)?;
+
+    fetch_quota(&client, target.as_ref()).await
+}
+
+// target_dir 以下に total_size バイト書き込めるか、転送を始める前に確かめる
+pub async fn ensure_quota(
+    profile_name: &str,
+    client_hub: &ClientHub,
+    target_dir: impl AsRef<Path>,
+    total_size: u64,
+) -> Result<()> {
+    let quota = quota(profile_name, client_hub, target_dir).await?;
+
+    if !quota.can_store(total_size) {
+        return Err(InsufficientQuota {
+            required: total_size,
+            available: quota.available.unwrap_or(0),
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quota_xml_test() {
+        let xml = r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:"><d:response><d:href>/remote.php/dav/files/user/</d:href>
+<d:propstat><d:prop><d:quota-available-bytes>1000</d:quota-available-bytes><d:quota-used-bytes>24</d:quota-used-bytes></d:prop>
+<d:status>HTTP/1.1 200 OK</d:status></d:propstat></d:response></d:multistatus>"#;
+        let document = roxmltree::Document::parse(xml).unwrap();
+        let quota = webdav_xml2quota(&document).unwrap();
+        assert_eq!(quota.used, 24);
+        assert_eq!(quota.available, Some(1000));
+        assert!(quota.can_store(1000));
+        assert!(!quota.can_store(1001));
+
+        // 共有フォルダなど
+        let xml = r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:"><d:response><d:href>/remote.php/dav/files/user/shared/</d:href>
+<d:propstat><d:prop><d:quota-used-bytes>24</d:quota-used-bytes></d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat>
+<d:propstat><d:prop><d:quota-available-bytes/></d:prop><d:status>HTTP/1.1 404 Not Found</d:status></d:propstat>
+</d:response></d:multistatus>"#;
+        let document = roxmltree::Document::parse(xml).unwrap();
+        let quota = webdav_xml2quota(&document).unwrap();
+        assert_eq!(quota.available, None);
+        assert!(quota.can_store(u64::MAX));
+    }
+}
@@ -1,23 +1,58 @@
+use crate::communicate::check_response;
+use crate::entry::Etag;
 use crate::path::AsNCUrl;
 use crate::setting::ClientHub;
 use anyhow::Result;
 use reqwest::Method;
 use std::path::Path;
 
+// サーバーが返したETagを返す
 pub async fn upload(
     profile_name: &str,
     client_hub: &ClientHub,
     path: impl AsRef<Path>,
     bytes: Vec<u8>,
-) -> Result<()> {
+) -> Result<Option<Etag>> {
     let ref client = client_hub.get_client(profile_name)?;
 
     let url = path.as_ref().as_nc_url(client)?;
-    let _ = client
-        .get_request_builder(Method::PUT, url)?
+    let res = client
+        .get_request_builder(Method::PUT, url)
+        .await?
         .body(bytes)
         .send()
         .await?;
+    let res = check_response(client, res)?;
+
+    let etag = res
+        .headers()
+        .get("OC-ETag")
+        .or_else(|| res.headers().get("ETag"))
+        .and_then(|v| v.to_str().ok())
+        .map(Etag::new);
+
+    Ok(etag)
+}
+
+// 既に存在する (405) 場合も成功とみなす
+pub async fn mkdir(
+    profile_name: &str,
+    client_hub: &ClientHub,
+    path: impl AsRef<Path>,
+) -> Result<()> {
+    let client = &client_hub.get_client(profile_name)?;
+
+    let url = path.as_ref().as_nc_url(client)?;
+    let res = client
+        .get_request_builder(Method::from_bytes(b"MKCOL").unwrap(), url)
+        .await?
+        .send()
+        .await?;
+
+    if res.status().as_u16() == 405 {
+        return Ok(());
+    }
+    check_response(client, res)?;
 
     Ok(())
 }
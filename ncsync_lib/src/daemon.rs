@@ -0,0 +1,640 @@
+use crate::communicate::push::{listen, PushEvent, PushOption};
+use crate::errors::NcsError::*;
+use crate::path::normalize_path;
+use crate::setting::{ClientHub, FolderPair, LocalInfo};
+use crate::sync::{placeholder_path, state_path, sync_once, SyncReport, SyncState};
+use crate::watch::{watch, WatchOption};
+use anyhow::Result;
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc;
+use tokio::time::{sleep_until, Instant};
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(300);
+const MAX_RECENT_ERRORS: usize = 100;
+// 同期が手元に書いたものの変更通知は、同期が終わってからこの間は無視する。
+// watch の debounce と max_delay より長くする
+const OWN_WRITES_WINDOW: Duration = Duration::from_secs(10);
+
+// 同期する folder pair は LocalInfo から取る。1つの folder pair を1つの job として扱う
+#[derive(Debug, Clone)]
+pub struct DaemonConfig {
+    pub socket_path: PathBuf,
+    // schedule が Default の folder pair はこの間隔で同期する。変更通知があればその都度同期する
+    pub interval: Duration,
+    // 指定すれば folder pair ごとの同期状態を <name>.json として保存し、再起動後も引き継ぐ
+    pub state_dir: Option<PathBuf>,
+}
+
+impl DaemonConfig {
+    pub fn new(socket_path: impl AsRef<Path>) -> Self {
+        Self {
+            socket_path: socket_path.as_ref().to_path_buf(),
+            interval: DEFAULT_INTERVAL,
+            state_dir: None,
+        }
+    }
+
+    fn state_path(&self, folder_pair: &FolderPair) -> Result<Option<PathBuf>> {
+        self.state_dir
+            .as_ref()
+            .map(|dir| state_path(dir, folder_pair))
+            .transpose()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Idle,
+    Syncing,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobStatus {
+    pub name: String,
+    pub profile: String,
+    pub state: JobState,
+    // RFC 3339
+    pub last_sync: Option<String>,
+    pub last_report: Option<SyncReport>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DaemonStatus {
+    pub paused: bool,
+    pub jobs: Vec<JobStatus>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorRecord {
+    pub time: String,
+    pub job: String,
+    pub message: String,
+}
+
+// 制御ソケットには1行に1つのJSONを送り、1行のJSONで返事を受け取る
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlRequest {
+    Status,
+    Pause,
+    Resume,
+    // job が無ければ全job
+    ForceSync { job: Option<String> },
+    RecentErrors,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum ControlResponse {
+    Ok,
+    Status(DaemonStatus),
+    RecentErrors { errors: Vec<ErrorRecord> },
+    Error { message: String },
+}
+
+#[derive(Debug)]
+enum Trigger {
+    // ForceSync。schedule が Manual のものも同期する
+    All,
+    Job(String),
+    // 変更通知と変更された sync_path。schedule が Manual のものは同期しない
+    LocalChanged(String, Vec<PathBuf>),
+    RemoteChanged(String),
+    // 何も積まずに sync_loop を起こす
+    Wake,
+}
+
+#[derive(Debug)]
+struct Shared {
+    status: DaemonStatus,
+    errors: VecDeque<ErrorRecord>,
+}
+
+impl Shared {
+    fn new(jobs: &[FolderPair]) -> Self {
+        let jobs = jobs
+            .iter()
+            .map(|job| JobStatus {
+                name: job.name.clone(),
+                profile: job.profile.clone(),
+                state: JobState::Idle,
+                last_sync: None,
+                last_report: None,
+            })
+            .collect();
+        Self {
+            status: DaemonStatus {
+                paused: false,
+                jobs,
+            },
+            errors: VecDeque::new(),
+        }
+    }
+
+    fn job_mut(&mut self, name: &str) -> Option<&mut JobStatus> {
+        self.status.jobs.iter_mut().find(|j| j.name == name)
+    }
+
+    fn push_error(&mut self, job: &str, message: String) {
+        if self.errors.len() >= MAX_RECENT_ERRORS {
+            self.errors.pop_front();
+        }
+        self.errors.push_back(ErrorRecord {
+            time: Local::now().to_rfc3339(),
+            job: job.to_string(),
+            message,
+        });
+    }
+}
+
+type SharedRef = Arc<Mutex<Shared>>;
+
+fn respond(
+    request: ControlRequest,
+    shared: &SharedRef,
+    triggers: &mpsc::UnboundedSender<Trigger>,
+) -> Result<ControlResponse> {
+    let mut shared = shared.lock().map_err(|_| LockError)?;
+
+    let response = match request {
+        ControlRequest::Status => ControlResponse::Status(shared.status.clone()),
+        ControlRequest::RecentErrors => ControlResponse::RecentErrors {
+            errors: shared.errors.iter().cloned().collect(),
+        },
+        ControlRequest::Pause => {
+            shared.status.paused = true;
+            ControlResponse::Ok
+        }
+        ControlRequest::Resume => {
+            shared.status.paused = false;
+            triggers.send(Trigger::Wake)?;
+            ControlResponse::Ok
+        }
+        ControlRequest::ForceSync { .. } if shared.status.paused => ControlResponse::Error {
+            message: "daemon is paused".to_string(),
+        },
+        ControlRequest::ForceSync { job: None } => {
+            triggers.send(Trigger::All)?;
+            ControlResponse::Ok
+        }
+        ControlRequest::ForceSync { job: Some(job) } => {
+            if shared.job_mut(&job).is_none() {
+                ControlResponse::Error {
+                    message: format!("job not found: {}", job),
+                }
+            } else {
+                triggers.send(Trigger::Job(job))?;
+                ControlResponse::Ok
+            }
+        }
+    };
+
+    Ok(response)
+}
+
+async fn handle_connection(
+    stream: UnixStream,
+    shared: SharedRef,
+    triggers: mpsc::UnboundedSender<Trigger>,
+) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let response = match serde_json::from_str::<ControlRequest>(&line) {
+            Ok(request) => respond(request, &shared, &triggers)?,
+            Err(e) => ControlResponse::Error {
+                message: e.to_string(),
+            },
+        };
+        let mut response = serde_json::to_string(&response)?;
+        response.push('\n');
+        writer.write_all(response.as_bytes()).await?;
+    }
+
+    Ok(())
+}
+
+async fn serve(
+    listener: UnixListener,
+    shared: SharedRef,
+    triggers: mpsc::UnboundedSender<Trigger>,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let shared = shared.clone();
+                let triggers = triggers.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_connection(stream, shared, triggers).await {
+                        log::warn!("control connection: {:?}", e);
+                    }
+                });
+            }
+            Err(e) => log::warn!("control socket: {:?}", e),
+        }
+    }
+}
+
+// 残っているソケットファイルは、繋がらなければ前回の残骸とみなして消す
+async fn bind(socket_path: &Path) -> Result<UnixListener> {
+    if socket_path.exists() {
+        if UnixStream::connect(socket_path).await.is_ok() {
+            return Err(anyhow!(
+                "another daemon is listening on {}",
+                socket_path.display()
+            ));
+        }
+        std::fs::remove_file(socket_path)?;
+    }
+
+    // 他のユーザーが繋げる隙間を作らないよう、0700 のディレクトリの中で作って権限を絞ってから移す
+    let file_name = socket_path
+        .file_name()
+        .ok_or_else(|| InvalidPathError(socket_path.to_string_lossy().to_string()))?;
+    let private_dir = socket_path.with_file_name(format!(
+        ".{}.{}",
+        file_name.to_string_lossy(),
+        uuid::Uuid::new_v4().simple()
+    ));
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&private_dir)?;
+
+    let tmp_path = private_dir.join("socket");
+    let res = UnixListener::bind(&tmp_path)
+        .and_then(|listener| {
+            std::fs::set_permissions(&tmp_path, std::fs::Permissions::from_mode(0o600))?;
+            std::fs::rename(&tmp_path, socket_path)?;
+            Ok(listener)
+        })
+        .map_err(Into::into);
+    let _ = std::fs::remove_dir_all(&private_dir);
+
+    res
+}
+
+// 同期できたら、その SyncReport を返す
+async fn run_job(
+    client_hub: &ClientHub,
+    local_info: &LocalInfo,
+    config: &DaemonConfig,
+    job: &FolderPair,
+    state: &mut SyncState,
+    shared: &SharedRef,
+) -> Result<Option<SyncReport>> {
+    if let Some(status) = shared.lock().map_err(|_| LockError)?.job_mut(&job.name) {
+        status.state = JobState::Syncing;
+    }
+
+    let exclude_list = local_info.get_pair_exclude_list(job);
+    let res = sync_once(client_hub, job, exclude_list, state).await;
+    let saved = match config.state_path(job) {
+        Ok(Some(path)) => state.save(path),
+        Ok(None) => Ok(()),
+        Err(e) => Err(e),
+    };
+
+    let mut shared = shared.lock().map_err(|_| LockError)?;
+    if let Err(e) = saved {
+        shared.push_error(&job.name, format!("could not save state: {:?}", e));
+    }
+    let (job_state, report) = match res {
+        Ok(report) => {
+            for e in report.errors.iter() {
+                shared.push_error(&job.name, e.clone());
+            }
+            (JobState::Idle, Some(report))
+        }
+        Err(e) => {
+            log::warn!("sync {} failed: {:?}", job.name, e);
+            shared.push_error(&job.name, format!("{:?}", e));
+            (JobState::Failed, None)
+        }
+    };
+    if let Some(status) = shared.job_mut(&job.name) {
+        status.state = job_state;
+        if report.is_some() {
+            status.last_sync = Some(Local::now().to_rfc3339());
+            status.last_report = report.clone();
+        }
+    }
+
+    Ok(report)
+}
+
+// 同期で手元に書いたり消したりした sync_path (正規化したもの)。
+// 消した placeholder と、作ったり消したりしたかもしれない親ディレクトリも含める
+fn local_writes(report: &SyncReport) -> HashSet<PathBuf> {
+    let renamed = report
+        .invalid_names
+        .iter()
+        .filter_map(|i| Some([&i.path, i.renamed_to.as_ref()?]))
+        .flatten();
+    let written = report
+        .downloaded
+        .iter()
+        .chain(report.conflicts.iter())
+        .chain(report.deleted_local.iter())
+        .chain(renamed)
+        .flat_map(|p| vec![p.clone(), placeholder_path(p)]);
+    // placeholder は本来の名前には何も書いていない
+    let stubs = report.placeholders.iter().map(placeholder_path);
+
+    let mut res = written.chain(stubs).collect::<HashSet<_>>();
+    let dirs = res
+        .iter()
+        .flat_map(|p| p.ancestors().skip(1))
+        .filter(|p| *p != Path::new("/"))
+        .map(Path::to_path_buf)
+        .collect::<Vec<_>>();
+    res.extend(dirs);
+    res
+}
+
+// 自分で書いたものを除いても変更が残っているか
+fn has_foreign_changes(
+    own_writes: &HashMap<String, (Instant, HashSet<PathBuf>)>,
+    name: &str,
+    paths: &[PathBuf],
+) -> bool {
+    match own_writes.get(name) {
+        Some((until, written)) if Instant::now() < *until => {
+            paths.iter().any(|p| !written.contains(&normalize_path(p)))
+        }
+        _ => !paths.is_empty(),
+    }
+}
+
+async fn sleep_until_next(next: &HashMap<String, Instant>) {
+    match next.values().min() {
+        Some(at) => sleep_until(*at).await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn sync_loop(
+    client_hub: &ClientHub,
+    local_info: &LocalInfo,
+    config: &DaemonConfig,
+    shared: &SharedRef,
+    mut triggers: mpsc::UnboundedReceiver<Trigger>,
+) -> Result<()> {
+    let jobs = local_info.get_folder_pairs();
+    let mut states = HashMap::new();
+    for job in jobs.iter() {
+        let state = match config.state_path(job)? {
+            // 壊れていても daemon は止めず、その job だけ最初から同期し直す
+            Some(path) => SyncState::load(&path).unwrap_or_else(|e| {
+                log::warn!("could not load {}: {:?}", path.display(), e);
+                if let Ok(mut shared) = shared.lock() {
+                    shared.push_error(&job.name, format!("could not load state: {:?}", e));
+                }
+                SyncState::default()
+            }),
+            None => SyncState::default(),
+        };
+        states.insert(job.name.clone(), state);
+    }
+    let interval = |job: &FolderPair| job.schedule.interval(config.interval);
+    let mut next = HashMap::new();
+
+    // 起動時に Manual 以外を一度同期する
+    let mut pending = jobs
+        .iter()
+        .filter(|j| interval(j).is_some())
+        .map(|j| j.name.clone())
+        .collect::<HashSet<_>>();
+
+    // job の名前 -> (いつまで, 最後の同期で手元に書いたもの)
+    let mut own_writes = HashMap::new();
+
+    let enqueue = |pending: &mut HashSet<String>,
+                   own_writes: &HashMap<String, (Instant, HashSet<PathBuf>)>,
+                   trigger: Trigger| {
+        let names = jobs.iter().filter(|j| match &trigger {
+            Trigger::All => true,
+            Trigger::Job(name) => &j.name == name,
+            Trigger::LocalChanged(name, paths) => {
+                &j.name == name
+                    && interval(j).is_some()
+                    && has_foreign_changes(own_writes, name, paths)
+            }
+            Trigger::RemoteChanged(profile) => &j.profile == profile && interval(j).is_some(),
+            Trigger::Wake => false,
+        });
+        pending.extend(names.map(|j| j.name.clone()));
+    };
+
+    loop {
+        let paused = shared.lock().map_err(|_| LockError)?.status.paused;
+        if !paused {
+            for job in jobs.iter().filter(|j| pending.contains(&j.name)) {
+                let state = states.entry(job.name.clone()).or_default();
+                if let Some(report) =
+                    run_job(client_hub, local_info, config, job, state, shared).await?
+                {
+                    let until = Instant::now() + OWN_WRITES_WINDOW;
+                    own_writes.insert(job.name.clone(), (until, local_writes(&report)));
+                }
+                if let Some(interval) = interval(job) {
+                    next.insert(job.name.clone(), Instant::now() + interval);
+                }
+            }
+            pending.clear();
+        }
+
+        tokio::select! {
+            _ = sleep_until_next(&next) => {
+                let now = Instant::now();
+                for (name, at) in next.iter() {
+                    if *at <= now {
+                        pending.insert(name.clone());
+                    }
+                }
+                // 止まっている間に期限が来たものは再開したときに同期する
+                next.retain(|_, at| *at > now);
+            }
+            trigger = triggers.recv() => match trigger {
+                Some(trigger) => enqueue(&mut pending, &own_writes, trigger),
+                None => return Ok(()),
+            },
+        }
+        // 同期中に溜まったものもまとめて処理する
+        while let Ok(trigger) = triggers.try_recv() {
+            enqueue(&mut pending, &own_writes, trigger);
+        }
+    }
+}
+
+// notify_push の変更通知を同期のきっかけにする。定期的な同期は sync_loop が行うので Poll は無視する
+async fn forward_push(
+    profile_name: &str,
+    client_hub: &ClientHub,
+    shared: &SharedRef,
+    triggers: mpsc::UnboundedSender<Trigger>,
+) {
+    let (sender, mut receiver) = mpsc::channel(16);
+    let option = PushOption::default();
+
+    let forward = async {
+        while let Some(event) = receiver.recv().await {
+            if event == PushEvent::FilesChanged
+                && triggers
+                    .send(Trigger::RemoteChanged(profile_name.to_string()))
+                    .is_err()
+            {
+                break;
+            }
+        }
+    };
+    let (res, _) = tokio::join!(listen(profile_name, client_hub, &option, sender), forward);
+
+    if let Err(e) = res {
+        log::warn!("push listener for {} stopped: {:?}", profile_name, e);
+        if let Ok(mut shared) = shared.lock() {
+            shared.push_error(profile_name, format!("{:?}", e));
+        }
+    }
+    // 止まっても定期的な同期は続ける
+    std::future::pending::<()>().await;
+}
+
+// config.socket_path で制御を受け付けながら local_info の folder pair を同期し続ける
+pub async fn run(
+    client_hub: &ClientHub,
+    local_info: &LocalInfo,
+    config: DaemonConfig,
+) -> Result<()> {
+    let jobs = local_info.get_folder_pairs();
+    for job in jobs.iter() {
+        config.state_path(job)?;
+    }
+    let shared = Arc::new(Mutex::new(Shared::new(jobs)));
+    let (trigger_sender, trigger_receiver) = mpsc::unbounded_channel();
+
+    let listener = bind(&config.socket_path).await?;
+    tokio::spawn(serve(listener, shared.clone(), trigger_sender.clone()));
+
+    // 手元の変更はその job だけ同期する
+    let mut watchers = Vec::new();
+    for job in jobs.iter() {
+        match watch(
+            &job.local_root,
+            local_info.get_pair_exclude_list(job).clone(),
+            WatchOption::default(),
+        ) {
+            Ok((watcher, mut receiver)) => {
+                watchers.push(watcher);
+                let triggers = trigger_sender.clone();
+                let name = job.name.clone();
+                tokio::spawn(async move {
+                    while let Some(paths) = receiver.recv().await {
+                        if triggers
+                            .send(Trigger::LocalChanged(name.clone(), paths))
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                });
+            }
+            Err(e) => log::warn!("could not watch {}: {:?}", job.local_root.display(), e),
+        }
+    }
+
+    let mut profiles = jobs
+        .iter()
+        .map(|job| job.profile.clone())
+        .collect::<Vec<_>>();
+    profiles.sort();
+    profiles.dedup();
+    let listeners = futures_util::future::join_all(
+        profiles
+            .iter()
+            .map(|p| forward_push(p, client_hub, &shared, trigger_sender.clone())),
+    );
+    // folder pair が無いと join_all がすぐに終わってしまうので、そのときは待ち続ける
+    let listeners = async {
+        if profiles.is_empty() {
+            futures_util::future::pending::<()>().await;
+        }
+        listeners.await;
+    };
+
+    let res = tokio::select! {
+        res = sync_loop(client_hub, local_info, &config, &shared, trigger_receiver) => res,
+        _ = listeners => Ok(()),
+    };
+
+    drop(watchers);
+    let _ = std::fs::remove_file(&config.socket_path);
+
+    res
+}
+
+// トレイアプリやスクリプトから daemon に命令を送る
+pub async fn send_command(
+    socket_path: impl AsRef<Path>,
+    request: &ControlRequest,
+) -> Result<ControlResponse> {
+    let stream = UnixStream::connect(socket_path.as_ref()).await?;
+    let (reader, mut writer) = stream.into_split();
+
+    let mut request = serde_json::to_string(request)?;
+    request.push('\n');
+    writer.write_all(request.as_bytes()).await?;
+
+    let line = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await?
+        .ok_or_else(|| anyhow!("daemon closed the connection"))?;
+
+    Ok(serde_json::from_str(&line)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn own_writes_test() {
+        let report = SyncReport {
+            downloaded: vec![PathBuf::from("/a/b.txt")],
+            placeholders: vec![PathBuf::from("/c.txt")],
+            ..Default::default()
+        };
+        let mut own_writes = HashMap::new();
+        own_writes.insert(
+            "job".to_string(),
+            (Instant::now() + OWN_WRITES_WINDOW, local_writes(&report)),
+        );
+
+        let changed = |paths: &[&str]| {
+            let paths = paths.iter().map(PathBuf::from).collect::<Vec<_>>();
+            has_foreign_changes(&own_writes, "job", &paths)
+        };
+        assert!(!changed(&["/a", "/a/b.txt", "/c.txt.ncsync"]));
+        assert!(changed(&["/a/b.txt", "/a/other.txt"]));
+        assert!(changed(&["/c.txt"]));
+
+        // 時間が経てば自分で書いたものでも同期する
+        own_writes.get_mut("job").unwrap().0 = Instant::now();
+        assert!(has_foreign_changes(
+            &own_writes,
+            "job",
+            &[PathBuf::from("/a/b.txt")]
+        ));
+    }
+}
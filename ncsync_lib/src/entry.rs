@@ -1,8 +1,8 @@
-use crate::setting::ExcludeList;
+use crate::setting::{ExcludeList, FolderPair};
 use chrono::{DateTime, Local};
 use std::collections::HashMap;
 use std::fmt::Display;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Clone)]
 pub struct Etag {
@@ -117,18 +117,60 @@ impl Entry {
     }
 
     pub fn is_exclude_target(&self, exclude_list: &ExcludeList) -> bool {
-        !exclude_list.judge(&self.path)
+        !self.judged_by(exclude_list, &self.path)
+    }
+
+    // ファイルは大きさと更新日時でも判定する
+    fn judged_by(&self, exclude_list: &ExcludeList, path: &Path) -> bool {
+        match self.entry_type {
+            EntryType::File { .. } => {
+                exclude_list.judge_file(path, self.size as u64, self.last_modified)
+            }
+            EntryType::Dir { .. } => exclude_list.judge_dir(path),
+        }
     }
 
     pub fn get_tree(&self, exclude_list: &ExcludeList, verbose: bool) -> String {
         let mut res = String::new();
 
-        self.tree_rec(&mut res, "", exclude_list, verbose);
+        let marker = |e: &Entry| {
+            if e.is_exclude_target(exclude_list) {
+                "[EXCLUDE]"
+            } else {
+                ""
+            }
+        };
+        self.tree_rec(&mut res, "", &marker, verbose);
 
         res
     }
 
-    fn tree_rec(&self, tree: &mut String, indent: &str, exclude_list: &ExcludeList, verbose: bool) {
+    // folder pair の remote_root 以下のツリー。同期されないものに印を付ける
+    pub fn get_pair_tree(
+        &self,
+        folder_pair: &FolderPair,
+        exclude_list: &ExcludeList,
+        verbose: bool,
+    ) -> String {
+        let mut res = String::new();
+
+        let marker = |e: &Entry| match folder_pair.sync_path_from_remote(&e.path) {
+            Some(path) if !e.judged_by(exclude_list, &path) => "[EXCLUDE]",
+            Some(path) if !folder_pair.selective.is_synced(&path) => "[NOT SYNCED]",
+            _ => "",
+        };
+        self.tree_rec(&mut res, "", &marker, verbose);
+
+        res
+    }
+
+    fn tree_rec(
+        &self,
+        tree: &mut String,
+        indent: &str,
+        marker: &dyn Fn(&Entry) -> &'static str,
+        verbose: bool,
+    ) {
         let s = format!(
             "{} {}\n",
             if verbose {
@@ -136,11 +178,7 @@ impl Entry {
             } else {
                 self.get_name()
             },
-            if self.is_exclude_target(exclude_list) {
-                "[EXCLUDE]"
-            } else {
-                ""
-            }
+            marker(self)
         );
         tree.push_str(s.as_str());
 
@@ -170,7 +208,7 @@ impl Entry {
             c.tree_rec(
                 tree,
                 format!("{}{}   ", indent, if is_not_last { "│" } else { " " }).as_str(),
-                exclude_list,
+                marker,
                 verbose,
             );
         }
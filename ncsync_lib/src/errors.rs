@@ -1,5 +1,14 @@
+use crate::setting::PatternError;
 use thiserror::Error;
 
+fn list_pattern_errors(errors: &[PatternError]) -> String {
+    errors
+        .iter()
+        .map(|e| format!("  {}", e))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
 #[allow(dead_code)]
 #[derive(Error, Debug)]
 pub enum NcsError {
@@ -25,4 +34,24 @@ pub enum NcsError {
     ProfileNotFound(String),
     #[error("Invalid Profile. Please check profiles.toml")]
     InvalidProfile,
+    #[error("Login token expired. Please log in again.")]
+    LoginTimeout,
+    #[error("Login cancelled.")]
+    LoginCancelled,
+    #[error("Not enough quota. required: {required}B, available: {available}B")]
+    InsufficientQuota { required: u64, available: u64 },
+    #[error("Not a placeholder {0}.")]
+    NotPlaceholder(String),
+    #[error("Local changes not synced yet {0}.")]
+    UnsyncedLocalChange(String),
+    #[error("Invalid network setting for profile {0}: {1}")]
+    InvalidNetworkSetting(String, String),
+    #[error("Invalid folder pair name {0}.")]
+    InvalidFolderPairName(String),
+    #[error("interval_secs of folder pair {0} must be greater than 0. Set manual = true to sync only on request.")]
+    InvalidInterval(String),
+    #[error("Keyring is not available: {0}. Please set NCSYNC_CREDENTIAL_PASSPHRASE to use an encrypted file, or choose credential_backend in profiles.toml.")]
+    KeyringUnavailable(String),
+    #[error("Invalid exclude patterns.\n{}", list_pattern_errors(.0))]
+    InvalidExcludePatterns(Vec<PatternError>),
 }
@@ -5,11 +5,17 @@ extern crate anyhow;
 
 pub mod cli;
 pub mod communicate;
+#[cfg(unix)]
+pub mod daemon;
 mod entry;
 mod errors;
 pub mod login;
 mod path;
 pub mod setting;
+pub mod sync;
+pub mod watch;
+
+pub use errors::NcsError;
 
 #[cfg(test)]
 mod tests {
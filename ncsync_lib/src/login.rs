@@ -1,9 +1,18 @@
-use crate::setting::ClientHub;
+use crate::errors::NcsError::*;
+use crate::setting::Client;
 use anyhow::Result;
-use reqwest::Url;
+use reqwest::{Method, Url};
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::{sleep, Instant};
 
 const LOGINREQUESTURL: &str = "/index.php/login/v2";
+const APPPASSWORDURL: &str = "/ocs/v2.php/core/apppassword";
+const GETAPPPASSWORDURL: &str = "/ocs/v2.php/core/getapppassword";
+const CAPABILITIESURL: &str = "/ocs/v1.php/cloud/capabilities?format=json";
+const OAUTH2AUTHORIZEURL: &str = "/index.php/apps/oauth2/authorize";
+const OAUTH2TOKENURL: &str = "/index.php/apps/oauth2/api/v1/token";
 // const POLLINGURL: &str = "/login/v2/poll";
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -18,9 +27,8 @@ pub struct ReqLoginResponseJson {
     pub login: String,
 }
 
-pub async fn login_request(client_hub: &ClientHub, host: &str) -> Result<ReqLoginResponseJson> {
+pub async fn login_request(client: &reqwest::Client, host: &str) -> Result<ReqLoginResponseJson> {
     let host = Url::parse(host)?;
-    let client = client_hub.get_reqclient();
     let url = host.join(LOGINREQUESTURL)?;
     let res = client.post(url).send().await?;
     let json: ReqLoginResponseJson = res.json().await?;
@@ -38,12 +46,11 @@ pub struct PollResponseJson {
 }
 
 pub async fn polling(
-    client_hub: &ClientHub,
+    client: &reqwest::Client,
     token: &str,
     end_point: &str,
 ) -> Result<Option<PollResponseJson>> {
     let end_point = Url::parse(end_point)?;
-    let client = client_hub.get_reqclient();
     let res = client
         .post(end_point)
         .form(&[("token", token)])
@@ -58,3 +65,278 @@ pub async fn polling(
 
     Ok(Some(json))
 }
+
+// Login Flow v2 の token は20分で失効する
+pub const LOGIN_TOKEN_LIFETIME: Duration = Duration::from_secs(20 * 60);
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone)]
+pub struct LoginOption {
+    pub open_browser: bool,
+    pub poll_interval: Duration,
+}
+
+impl Default for LoginOption {
+    fn default() -> Self {
+        Self {
+            open_browser: false,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+        }
+    }
+}
+
+// 別タスクから polling_loop を止めるためのハンドル
+#[derive(Debug)]
+pub struct LoginCanceller {
+    sender: watch::Sender<bool>,
+}
+
+impl LoginCanceller {
+    pub fn cancel(&self) {
+        let _ = self.sender.send(true);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LoginCancelToken {
+    receiver: watch::Receiver<bool>,
+}
+
+impl LoginCancelToken {
+    pub fn is_cancelled(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    async fn cancelled(&mut self) {
+        while !self.is_cancelled() {
+            if self.receiver.changed().await.is_err() {
+                // Canceller が drop されたらもうキャンセルされることはない
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+}
+
+pub fn login_canceller() -> (LoginCanceller, LoginCancelToken) {
+    let (sender, receiver) = watch::channel(false);
+    (LoginCanceller { sender }, LoginCancelToken { receiver })
+}
+
+pub fn open_browser(url: &str) -> Result<()> {
+    webbrowser::open(url)?;
+    Ok(())
+}
+
+// issued_at は login_request を送った時刻。token はそこから LOGIN_TOKEN_LIFETIME で失効する
+pub async fn polling_loop(
+    client: &reqwest::Client,
+    token: &str,
+    end_point: &str,
+    issued_at: Instant,
+    poll_interval: Duration,
+    cancel: Option<LoginCancelToken>,
+) -> Result<PollResponseJson> {
+    let deadline = issued_at + LOGIN_TOKEN_LIFETIME;
+    let mut cancel = cancel;
+
+    loop {
+        if cancel.as_ref().map_or(false, |c| c.is_cancelled()) {
+            return Err(LoginCancelled.into());
+        }
+
+        match polling(client, token, end_point).await {
+            Ok(Some(res)) => return Ok(res),
+            Ok(None) => (),
+            // 一時的なネットワークエラーでは諦めない
+            Err(e) => log::warn!("polling failed: {:?}", e),
+        }
+
+        if Instant::now() + poll_interval >= deadline {
+            return Err(LoginTimeout.into());
+        }
+
+        match cancel {
+            Some(ref mut c) => {
+                tokio::select! {
+                    _ = sleep(poll_interval) => (),
+                    _ = c.cancelled() => return Err(LoginCancelled.into()),
+                }
+            }
+            None => sleep(poll_interval).await,
+        }
+    }
+}
+
+// app password を失効させるリクエスト。手元の認証情報を消してから送れるよう、組み立てるだけにする
+pub(crate) async fn delete_app_password_request(
+    client: &Client<'_>,
+) -> Result<reqwest::RequestBuilder> {
+    let host = client.get_host()?.ok_or(NotLoggedIn)?;
+    let url = host.join(APPPASSWORDURL)?;
+    client.get_request_builder(Method::DELETE, url).await
+}
+
+// 既に失効している (401) 場合も成功とみなす
+pub(crate) async fn delete_app_password(request: reqwest::RequestBuilder) -> Result<()> {
+    let res = request.send().await?;
+
+    match res.status().as_u16() {
+        200 | 401 => Ok(()),
+        status => Err(BadStatusError(status).into()),
+    }
+}
+
+// Nextcloud の OAuth2 アプリで登録した client の情報
+#[derive(Debug, Clone)]
+pub struct OAuth2Client {
+    pub client_id: String,
+    pub client_secret: String,
+    pub redirect_uri: String,
+}
+
+pub enum OAuth2Grant<'a> {
+    AuthorizationCode(&'a str),
+    RefreshToken(&'a str),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OAuth2TokenResponseJson {
+    pub access_token: String,
+    pub refresh_token: String,
+    pub expires_in: i64,
+    pub user_id: String,
+}
+
+pub fn oauth2_authorize_url(host: &str, oauth2_client: &OAuth2Client) -> Result<Url> {
+    let host = Url::parse(host)?;
+    let mut url = host.join(OAUTH2AUTHORIZEURL)?;
+    url.query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &oauth2_client.client_id)
+        .append_pair("redirect_uri", &oauth2_client.redirect_uri);
+    Ok(url)
+}
+
+pub async fn oauth2_request_token(
+    client: &reqwest::Client,
+    host: &str,
+    oauth2_client: &OAuth2Client,
+    grant: OAuth2Grant<'_>,
+) -> Result<OAuth2TokenResponseJson> {
+    let host = Url::parse(host)?;
+    let url = host.join(OAUTH2TOKENURL)?;
+    let mut form = vec![
+        ("client_id", oauth2_client.client_id.as_str()),
+        ("client_secret", oauth2_client.client_secret.as_str()),
+    ];
+    match grant {
+        OAuth2Grant::AuthorizationCode(code) => {
+            form.push(("grant_type", "authorization_code"));
+            form.push(("code", code));
+            form.push(("redirect_uri", oauth2_client.redirect_uri.as_str()));
+        }
+        OAuth2Grant::RefreshToken(refresh_token) => {
+            form.push(("grant_type", "refresh_token"));
+            form.push(("refresh_token", refresh_token));
+        }
+    }
+
+    let res = client.post(url).form(&form).send().await?;
+
+    match res.status().as_u16() {
+        200 => Ok(res.json().await?),
+        400 | 401 => Err(NotAuthorized.into()),
+        status => Err(BadStatusError(status).into()),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OcsResponseJson<T> {
+    ocs: OcsJson<T>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OcsJson<T> {
+    data: T,
+}
+
+#[derive(Debug, Deserialize)]
+struct AppPasswordJson {
+    apppassword: String,
+}
+
+// 通常のpasswordをapp passwordと交換する。
+// 既にapp passwordが渡された場合 (403) はそのまま返す
+pub async fn get_app_password(
+    client: &reqwest::Client,
+    host: &str,
+    username: &str,
+    password: &str,
+) -> Result<String> {
+    let host = Url::parse(host)?;
+    let url = host.join(GETAPPPASSWORDURL)?;
+    let res = client
+        .get(url)
+        .basic_auth(username, Some(password))
+        .header("Accept", "application/json")
+        .send()
+        .await?;
+
+    match res.status().as_u16() {
+        200 => {
+            let json: OcsResponseJson<AppPasswordJson> = res.json().await?;
+            Ok(json.ocs.data.apppassword)
+        }
+        403 => Ok(password.to_string()),
+        401 => Err(NotAuthorized.into()),
+        status => Err(BadStatusError(status).into()),
+    }
+}
+
+// capabilitiesを取得できるかで認証情報を確かめる
+pub async fn verify_app_password(
+    client: &reqwest::Client,
+    host: &str,
+    username: &str,
+    app_password: &str,
+) -> Result<()> {
+    let host = Url::parse(host)?;
+    let url = host.join(CAPABILITIESURL)?;
+    let res = client
+        .get(url)
+        .basic_auth(username, Some(app_password))
+        .send()
+        .await?;
+
+    match res.status().as_u16() {
+        200 => Ok(()),
+        401 => Err(NotAuthorized.into()),
+        status => Err(BadStatusError(status).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn polling_deadline_test() {
+        let issued_at = Instant::now();
+        sleep(Duration::from_millis(50)).await;
+
+        // 期限は polling を始めた時刻ではなく token を受け取った時刻から数える
+        let res = polling_loop(
+            &reqwest::Client::new(),
+            "token",
+            "not a url",
+            issued_at,
+            LOGIN_TOKEN_LIFETIME - Duration::from_millis(10),
+            None,
+        )
+        .await;
+        assert!(matches!(
+            res.unwrap_err().downcast_ref(),
+            Some(LoginTimeout)
+        ));
+    }
+}
@@ -6,30 +6,52 @@ use once_cell::sync::Lazy;
 use regex::Regex;
 use reqwest::Url;
 use std::path::{Path, PathBuf};
+use unicode_normalization::UnicodeNormalization;
 
 pub(crate) trait AsNCUrl {
     fn as_nc_url(&self, client: &Client<'_>) -> Result<Url>;
 }
 
+// 要素ごとに percent-encode する。"#" や "?" も URL の区切りと見なされないようにする
+fn encode_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| urlencoding::encode(segment).into_owned())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn decode_path(path: &str) -> Result<String> {
+    let segments = path
+        .split('/')
+        .map(|segment| urlencoding::decode(segment).map(|s| s.into_owned()))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    Ok(segments.join("/"))
+}
+
+fn nc_url(host: &Url, prefix: &str, path: &Path) -> Result<Url> {
+    let input = path.to_string_lossy().replace("\\", "/");
+    let input = input.trim_start_matches("/");
+    let path = format!("{}/{}", prefix.trim_end_matches("/"), input);
+    let res = host.join(&encode_path(&path))?;
+    Ok(res)
+}
+
 // もう少しバリデーションを追加する必要が出てくるかもしれない
 impl AsNCUrl for Path {
     fn as_nc_url(&self, client: &Client<'_>) -> Result<Url> {
-        let input = self.to_string_lossy().replace("\\", "/");
-        let input = input.trim_start_matches("/");
         let prefix = client
             .get_root_prefix()
             .context("Cloud Not Get Prefix! Did you login?")?;
-        let prefix = Path::new(&prefix);
-        let path = prefix.join(input);
         let host = client
             .get_host()?
             .context("Cloud Not Get Host! Did you login?")?;
-        let res = host.join(&path.to_string_lossy())?;
-        Ok(res)
+        nc_url(&host, &prefix, self)
     }
 }
 
+// url は WebDAV の href のように encode されたまま渡す。prefix は encode しないもの
 pub fn url2path(url: &str, prefix: &str) -> Result<PathBuf> {
+    let url = decode_path(url)?;
     let path = url.strip_prefix(prefix).context("Invalid URL @ url2path")?;
     // let path = path.trim_start_matches("/");
     let path = path.trim_end_matches("/");
@@ -44,6 +66,27 @@ pub fn url2path(url: &str, prefix: &str) -> Result<PathBuf> {
     Ok(path)
 }
 
+/*
+macOS で作られた名前は NFD になっていることがあり、Linux の NFC のものとバイト列が違う。
+手元とリモートのパスを比べるときはどちらも NFC にそろえる。
+サーバーや手元のファイルを触るときは元の名前を使うこと。
+*/
+pub(crate) fn normalize_path(path: &Path) -> PathBuf {
+    match path.to_str() {
+        Some(s) => PathBuf::from(s.nfc().collect::<String>()),
+        // UTF-8 でない名前はそのまま比べる
+        None => path.to_path_buf(),
+    }
+}
+
+// 大文字小文字を区別しないファイルシステムで同じものとして扱われるパスは同じ値になる
+pub(crate) fn collision_key(path: &Path) -> String {
+    path.to_string_lossy()
+        .nfc()
+        .collect::<String>()
+        .to_lowercase()
+}
+
 pub fn check_absolute(path: impl AsRef<Path>) -> bool {
     let path = path.as_ref();
     path.is_absolute()
@@ -102,3 +145,71 @@ impl<'a> NCPath<'a> {
         Ok(Self::new(path, profile))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PREFIX: &str = "/remote.php/dav/files/john doe";
+
+    fn round_trip(path: &str) {
+        let host = Url::parse("https://cloud.example.com/").unwrap();
+        let url = nc_url(&host, PREFIX, Path::new(path)).unwrap();
+
+        assert_eq!(url.host_str(), Some("cloud.example.com"));
+        assert_eq!(url.query(), None, "{}", path);
+        assert_eq!(url.fragment(), None, "{}", path);
+        assert_eq!(url2path(url.path(), PREFIX).unwrap(), Path::new(path));
+    }
+
+    #[test]
+    fn url_round_trip_test() {
+        for path in [
+            "/a/b.txt",
+            "/with space/file name.txt",
+            "/100%/50% off.pdf",
+            "/%2F/%41",
+            "/issue #12/notes#1.md",
+            "/what?/why?.txt",
+            "/a&b=c;d+e/[x]@y,z!$'()*.txt",
+            "/日本語/ファイル.txt",
+            "/emoji 🎉/👍🏽.png",
+            "/cafe\u{301}/caf\u{e9}.txt",
+        ] {
+            round_trip(path);
+        }
+    }
+
+    #[test]
+    fn url_encode_test() {
+        let host = Url::parse("https://cloud.example.com/").unwrap();
+        let url = nc_url(&host, PREFIX, Path::new("/a #1/b?.txt")).unwrap();
+
+        assert_eq!(
+            url.as_str(),
+            "https://cloud.example.com/remote.php/dav/files/john%20doe/a%20%231/b%3F.txt"
+        );
+        assert_eq!(
+            url2path("/remote.php/dav/files/john%20doe/dir/", PREFIX).unwrap(),
+            Path::new("/dir")
+        );
+    }
+
+    #[test]
+    fn normalize_test() {
+        assert_eq!(
+            normalize_path(Path::new("/cafe\u{301}/a.txt")),
+            PathBuf::from("/caf\u{e9}/a.txt")
+        );
+        assert_eq!(normalize_path(Path::new("/Abc")), PathBuf::from("/Abc"));
+
+        assert_eq!(
+            collision_key(Path::new("/CAFE\u{301}/A.txt")),
+            collision_key(Path::new("/caf\u{e9}/a.txt"))
+        );
+        assert_ne!(
+            collision_key(Path::new("/a.txt")),
+            collision_key(Path::new("/a.txt.bak"))
+        );
+    }
+}
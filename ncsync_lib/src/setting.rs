@@ -1,17 +1,35 @@
 use anyhow::Result;
 // use once_cell::sync::Lazy;
+use crate::communicate::capabilities::Capabilities;
 use crate::errors::NcsError::*;
+use crate::login::{self, OAuth2Client, OAuth2Grant};
+use chrono::{DateTime, Local, NaiveDate, TimeZone};
 use regex::Regex;
 use reqwest::{Method, Url};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::default::Default;
+use std::fmt::Display;
+use std::sync::Mutex;
+use tokio::sync::mpsc;
 use uuid::Uuid;
 
+pub mod auth;
+pub mod credential;
+pub mod folder_pair;
+pub mod ignore_file;
+pub mod network;
 pub mod readwrite;
 
+use auth::Auth;
+pub use auth::OAuth2Token;
+pub use credential::{CredentialBackend, CredentialStore};
+pub use folder_pair::{FolderPair, InvalidNamePolicy, Schedule, SelectiveSync, SyncDirection};
+pub use ignore_file::{IgnoreFiles, IgnoreMatch, IGNORE_FILE_NAME};
+pub use network::{NetworkSetting, TlsSetting};
+
 const NC_ROOT_PREFIX: &str = "/remote.php/dav/files/";
-pub const OCS_ROOT: &str = "/ocs/v2.php/apps/activity/api/v2/activity/all";
+pub const OCS_ROOT: &str = "/ocs/v2.php/apps/activity/api/v2/activity";
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", content = "info")]
@@ -26,22 +44,61 @@ pub enum LoginStatus {
         username: String,
         password: String,
     },
+    OAuth2 {
+        host: String,
+        username: String,
+        client_id: String,
+        client_secret: String,
+        redirect_uri: String,
+        token: OAuth2Token,
+    },
+}
+
+impl LoginStatus {
+    pub fn get_username(&self) -> Option<&str> {
+        match self {
+            LoginStatus::LoggedIn { username, .. } | LoginStatus::OAuth2 { username, .. } => {
+                Some(username)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn get_host_str(&self) -> Option<&str> {
+        match self {
+            LoginStatus::LoggedIn { host, .. } | LoginStatus::OAuth2 { host, .. } => Some(host),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Profile {
     pub name: String,
     pub login_status: LoginStatus,
+    // activity feed のカーソル。profiles.toml に保存する
+    pub last_activity_id: Option<u64>,
+    // reqwest::Client の再構築が必要なので ClientHub::set_network_setting 経由で変更する
+    network: NetworkSetting,
 }
 
 impl Profile {
     pub fn new(name: String) -> Self {
         let login_status = LoginStatus::NotYet;
-        Self { name, login_status }
+        Self::load(name, login_status)
     }
 
     pub fn load(name: String, login_status: LoginStatus) -> Self {
-        Self { name, login_status }
+        Self {
+            name,
+            login_status,
+            last_activity_id: None,
+            network: NetworkSetting::default(),
+        }
+    }
+
+    pub fn get_network_setting(&self) -> &NetworkSetting {
+        &self.network
     }
 
     fn make_root_prefix(username: &str) -> String {
@@ -50,11 +107,8 @@ impl Profile {
     }
 
     pub fn get_root_prefix(&self) -> Result<String> {
-        let username = match &self.login_status {
-            LoginStatus::LoggedIn { username, .. } => username.clone(),
-            _ => return Err(NotLoggedIn.into()),
-        };
-        let root_prefix = Profile::make_root_prefix(&username);
+        let username = self.login_status.get_username().ok_or(NotLoggedIn)?;
+        let root_prefix = Profile::make_root_prefix(username);
 
         Ok(root_prefix)
     }
@@ -74,33 +128,33 @@ impl Profile {
     }
 
     pub fn get_host(&self) -> Result<Option<Url>> {
-        let host = match self.login_status {
-            LoginStatus::LoggedIn { ref host, .. } => host,
-            _ => return Ok(None),
+        let host = match self.login_status.get_host_str() {
+            Some(host) => host,
+            None => return Ok(None),
         };
 
-        let host = fix_host(&host);
-        let host = Url::parse(&host)?;
+        let host = fix_host(host);
+        let host = Url::parse(host)?;
 
         Ok(Some(host))
     }
 
-    pub fn get_request_builder(
-        &self,
-        method: Method,
-        url: Url,
-        req_client: &reqwest::Client,
-    ) -> Result<reqwest::RequestBuilder> {
-        let (username, password) = match &self.login_status {
+    // OAuth2 の token は更新せずに使う。更新が必要なら Client::get_request_builder を使うこと
+    pub(crate) fn get_auth(&self) -> Result<Auth> {
+        let auth = match &self.login_status {
             LoginStatus::LoggedIn {
                 username, password, ..
-            } => (username, password),
-            _ => return Err(anyhow!("not logged in")),
+            } => Auth::Basic {
+                username: username.clone(),
+                password: password.clone(),
+            },
+            LoginStatus::OAuth2 { token, .. } => Auth::Bearer {
+                token: token.access_token.clone(),
+            },
+            _ => return Err(NotLoggedIn.into()),
         };
 
-        Ok(req_client
-            .request(method, url)
-            .basic_auth(username, Some(password)))
+        Ok(auth)
     }
 }
 
@@ -110,66 +164,157 @@ pub struct ClientHub {
     profiles: HashMap<String, Profile>,
     default_profile: Option<String>,
     req_client: reqwest::Client,
+    // profile name -> そのprofileのNetworkSettingを反映したclient
+    req_clients: HashMap<String, reqwest::Client>,
+    // NetworkSetting から client を作れなかった profile とその理由。直すまでその profile では通信しない
+    network_errors: HashMap<String, String>,
+    credential_backend: CredentialBackend,
+    credential_store: Box<dyn CredentialStore>,
+    // app passwordが失効していたprofile。操作は&ClientHubで行われるのでMutexで持つ
+    relogin_required: Mutex<HashSet<String>>,
+    // 再ログインが必要になった profile の名前を送る先
+    relogin_sender: Option<mpsc::UnboundedSender<String>>,
+    // 更新済みのOAuth2 token。LoginStatus::OAuth2 に入っているものより優先する
+    oauth2_tokens: Mutex<HashMap<String, OAuth2Token>>,
+    // refresh tokenは使い回せないので、更新は同時に一つだけ行う
+    oauth2_refresh_lock: tokio::sync::Mutex<()>,
+    // 更新したtokenを書き戻す先
+    toml_path: Option<PathBuf>,
+    capabilities: Mutex<HashMap<String, Capabilities>>,
 }
 
 impl ClientHub {
+    // 認証情報の保存先は CredentialBackend::detect で選ぶ。
+    // Keyring が使えなくても、認証情報を保存しようとするまではエラーにしない
     pub fn new() -> Result<Self> {
-        let client_id = Uuid::new_v4().to_string();
-        let req_client = Self::client_builder(&client_id).build()?;
-        let client_hub = Self {
-            profiles: HashMap::new(),
-            default_profile: None,
-            client_id,
-            req_client,
-        };
-        Ok(client_hub)
+        Self::load_without_profiles(Uuid::new_v4().to_string())
     }
 
     pub fn load_without_profiles(client_id: String) -> Result<Self> {
-        let req_client = Self::client_builder(&client_id).build()?;
+        let backend = CredentialBackend::detect(None).unwrap_or_default();
+        let store = backend.open()?;
+        Self::load_with_credential_store(client_id, backend, store)
+    }
+
+    pub(crate) fn load_with_credential_store(
+        client_id: String,
+        credential_backend: CredentialBackend,
+        credential_store: Box<dyn CredentialStore>,
+    ) -> Result<Self> {
+        let req_client = Self::build_reqclient_with(&client_id, &NetworkSetting::default())?;
         let client_hub = Self {
             profiles: HashMap::new(),
             default_profile: None,
             client_id,
             req_client,
+            req_clients: HashMap::new(),
+            network_errors: HashMap::new(),
+            credential_backend,
+            credential_store,
+            relogin_required: Mutex::new(HashSet::new()),
+            relogin_sender: None,
+            oauth2_tokens: Mutex::new(HashMap::new()),
+            oauth2_refresh_lock: tokio::sync::Mutex::new(()),
+            toml_path: None,
+            capabilities: Mutex::new(HashMap::new()),
         };
         Ok(client_hub)
     }
 
     pub fn add_profile(&mut self, name: String, login_status: LoginStatus) -> Result<()> {
+        self.add_profile_with_network(name, login_status, NetworkSetting::default())
+    }
+
+    pub fn add_profile_with_network(
+        &mut self,
+        name: String,
+        login_status: LoginStatus,
+        network: NetworkSetting,
+    ) -> Result<()> {
         if self.profiles.contains_key(&name) {
             return Err(anyhow!("profile already exists"));
         }
+        let req_client = self.build_reqclient(&network)?;
+        self.insert_profile(name, login_status, network, Ok(req_client));
+        Ok(())
+    }
+
+    // 設定ファイルから読むとき用。client を作れなくても profile は読み込み、その profile だけ使えなくする
+    pub(crate) fn load_profile(
+        &mut self,
+        name: String,
+        login_status: LoginStatus,
+        network: NetworkSetting,
+    ) -> Result<()> {
+        if self.profiles.contains_key(&name) {
+            return Err(anyhow!("profile already exists"));
+        }
+        let req_client = self.build_reqclient(&network).map_err(|e| {
+            log::warn!("profile {}: {:?}", name, e);
+            format!("{:#}", e)
+        });
+        self.insert_profile(name, login_status, network, req_client);
+        Ok(())
+    }
 
+    fn insert_profile(
+        &mut self,
+        name: String,
+        login_status: LoginStatus,
+        network: NetworkSetting,
+        req_client: std::result::Result<reqwest::Client, String>,
+    ) {
         if self.profiles.is_empty() {
             self.default_profile = Some(name.clone());
         }
 
-        self.profiles
-            .insert(name.clone(), Profile::load(name, login_status));
+        let mut profile = Profile::load(name.clone(), login_status);
+        profile.network = network;
+        match req_client {
+            Ok(req_client) => {
+                self.req_clients.insert(name.clone(), req_client);
+            }
+            Err(e) => {
+                self.network_errors.insert(name.clone(), e);
+            }
+        }
+        self.profiles.insert(name, profile);
+    }
+
+    pub fn get_network_error(&self, profile_name: &str) -> Option<&str> {
+        self.network_errors.get(profile_name).map(|e| e.as_str())
+    }
+
+    pub fn set_network_setting(
+        &mut self,
+        profile_name: &str,
+        network: NetworkSetting,
+    ) -> Result<()> {
+        if !self.profiles.contains_key(profile_name) {
+            return Err(ProfileNotFound(profile_name.to_string()).into());
+        }
+
+        let req_client = self.build_reqclient(&network)?;
+        self.req_clients
+            .insert(profile_name.to_string(), req_client);
+        self.network_errors.remove(profile_name);
+        self.profiles.get_mut(profile_name).unwrap().network = network;
         Ok(())
     }
 
     pub fn new_with_auth(username: String, password: String, host: String) -> Result<Self> {
         // let host = fix_host(&host);
         // let host = Url::parse(&host)?;
-        let client_id = Uuid::new_v4().to_string();
-        let profile = Profile::load(
+        let mut hub = Self::new()?;
+        hub.add_profile(
             username.clone(),
             LoginStatus::LoggedIn {
                 host,
-                username: username.clone(),
+                username,
                 password,
             },
-        );
-        let default_profile = Some(username.clone());
-        let profiles = vec![(username, profile)].into_iter().collect();
-        Ok(Self {
-            client_id: client_id.clone(),
-            req_client: Self::client_builder(&client_id).build()?,
-            profiles,
-            default_profile,
-        })
+        )?;
+        Ok(hub)
     }
 
     pub(crate) fn client_builder(client_id: &str) -> reqwest::ClientBuilder {
@@ -192,8 +337,142 @@ impl ClientHub {
         &self.req_client
     }
 
-    pub fn new_reqclient(&self, _proxy: Option<String>) -> Result<reqwest::Client> {
-        Ok(Self::client_builder(&self.client_id).build()?)
+    pub fn get_credential_backend(&self) -> &CredentialBackend {
+        &self.credential_backend
+    }
+
+    pub fn get_credential_store(&self) -> &dyn CredentialStore {
+        self.credential_store.as_ref()
+    }
+
+    // 既存の認証情報は移さない。移行は readwrite::migrate_credentials を使う
+    pub fn set_credential_store(
+        &mut self,
+        backend: CredentialBackend,
+        store: Box<dyn CredentialStore>,
+    ) -> Box<dyn CredentialStore> {
+        self.credential_backend = backend;
+        std::mem::replace(&mut self.credential_store, store)
+    }
+
+    // profile の再ログインが必要になったとき (401を受け取ったとき) に、その名前が一度だけ届く。
+    // Login Flow v2 は &mut ClientHub が要るので、受け取った側で操作を終えてから cli::reauth などを呼ぶ。
+    // 受け取れるのは最後に呼んだものだけ
+    pub fn subscribe_relogin(&mut self) -> mpsc::UnboundedReceiver<String> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.relogin_sender = Some(sender);
+        receiver
+    }
+
+    pub fn is_relogin_required(&self, profile_name: &str) -> bool {
+        match self.relogin_required.lock() {
+            Ok(set) => set.contains(profile_name),
+            Err(_) => false,
+        }
+    }
+
+    // 読み込み時や再ログイン失敗で既に印が付いていても、後から subscribe した側に届くよう毎回送る
+    pub(crate) fn mark_relogin_required(&self, profile_name: &str) -> Result<()> {
+        let newly = self
+            .relogin_required
+            .lock()
+            .map_err(|_| LockError)?
+            .insert(profile_name.to_string());
+
+        if newly {
+            log::warn!(
+                "{}: app password was revoked. Please log in again.",
+                profile_name
+            );
+        }
+        if let Some(sender) = &self.relogin_sender {
+            // 受け取る側が居なくなっていても構わない
+            let _ = sender.send(profile_name.to_string());
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn clear_relogin_required(&self, profile_name: &str) -> Result<()> {
+        self.relogin_required
+            .lock()
+            .map_err(|_| LockError)?
+            .remove(profile_name);
+        Ok(())
+    }
+
+    pub fn set_toml_path(&mut self, toml_path: impl AsRef<Path>) {
+        self.toml_path = Some(toml_path.as_ref().to_owned());
+    }
+
+    pub fn get_oauth2_token(&self, profile_name: &str) -> Option<OAuth2Token> {
+        if let Some(token) = self
+            .oauth2_tokens
+            .lock()
+            .ok()
+            .and_then(|tokens| tokens.get(profile_name).cloned())
+        {
+            return Some(token);
+        }
+
+        match self.get_profile(profile_name)?.login_status {
+            LoginStatus::OAuth2 { ref token, .. } => Some(token.clone()),
+            _ => None,
+        }
+    }
+
+    // LoginStatus::OAuth2 を新しくしたときは古い更新済みtokenを捨てる
+    pub(crate) fn clear_oauth2_token(&self, profile_name: &str) -> Result<()> {
+        self.oauth2_tokens
+            .lock()
+            .map_err(|_| LockError)?
+            .remove(profile_name);
+        Ok(())
+    }
+
+    pub fn get_cached_capabilities(&self, profile_name: &str) -> Option<Capabilities> {
+        self.capabilities
+            .lock()
+            .ok()
+            .and_then(|c| c.get(profile_name).cloned())
+    }
+
+    pub(crate) fn set_cached_capabilities(
+        &self,
+        profile_name: &str,
+        capabilities: Capabilities,
+    ) -> Result<()> {
+        self.capabilities
+            .lock()
+            .map_err(|_| LockError)?
+            .insert(profile_name.to_string(), capabilities);
+        Ok(())
+    }
+
+    // ログインし直すと接続先が変わりうるので捨てる
+    pub(crate) fn clear_cached_capabilities(&self, profile_name: &str) -> Result<()> {
+        self.capabilities
+            .lock()
+            .map_err(|_| LockError)?
+            .remove(profile_name);
+        Ok(())
+    }
+
+    pub fn get_profile_reqclient(&self, profile_name: &str) -> Option<&reqwest::Client> {
+        self.req_clients.get(profile_name)
+    }
+
+    pub fn new_reqclient(&self, proxy: Option<String>) -> Result<reqwest::Client> {
+        self.build_reqclient(&NetworkSetting::with_proxy(proxy))
+    }
+
+    fn build_reqclient(&self, network: &NetworkSetting) -> Result<reqwest::Client> {
+        Self::build_reqclient_with(&self.client_id, network)
+    }
+
+    fn build_reqclient_with(client_id: &str, network: &NetworkSetting) -> Result<reqwest::Client> {
+        let builder = network.apply(Self::client_builder(client_id))?;
+        Ok(builder.build()?)
     }
 
     pub fn get_all_profiles(&self) -> Vec<&Profile> {
@@ -234,10 +513,19 @@ impl ClientHub {
         }
     }
 
+    // 既定の client で代わりに通信すると proxy などを素通りしてしまうので、エラーにする
+    fn check_network_error(&self, profile_name: &str) -> Result<()> {
+        match self.network_errors.get(profile_name) {
+            Some(e) => Err(InvalidNetworkSetting(profile_name.to_string(), e.clone()).into()),
+            None => Ok(()),
+        }
+    }
+
     pub(crate) fn get_client(&self, profile_name: &str) -> Result<Client> {
         let profile = self
             .get_profile(profile_name)
             .ok_or_else(|| ProfileNotFound(profile_name.to_string()))?;
+        self.check_network_error(profile_name)?;
         let client = Client {
             client_hub: self,
             profile,
@@ -250,6 +538,7 @@ impl ClientHub {
             Some(_) => (),
             None => return Err(ProfileNotFound(profile_name.to_string()).into()),
         }
+        self.check_network_error(profile_name)?;
         let client = ClientMut {
             client_hub: self,
             profile_name: profile_name.to_string(),
@@ -273,7 +562,9 @@ impl<'a> Client<'a> {
     }*/
 
     pub fn get_reqclient(&'a self) -> &'a reqwest::Client {
-        &self.client_hub.req_client
+        self.client_hub
+            .get_profile_reqclient(&self.profile.name)
+            .unwrap_or(&self.client_hub.req_client)
     }
 
     /*
@@ -282,18 +573,96 @@ impl<'a> Client<'a> {
     }
     */
 
-    pub fn get_request_builder(&self, method: Method, url: Url) -> Result<reqwest::RequestBuilder> {
-        let (username, password) = match &self.profile.login_status {
-            LoginStatus::LoggedIn {
-                username, password, ..
-            } => (username, password),
-            _ => return Err(NotLoggedIn.into()),
-        };
+    pub async fn get_request_builder(
+        &self,
+        method: Method,
+        url: Url,
+    ) -> Result<reqwest::RequestBuilder> {
+        let auth = self.get_auth().await?;
         let req_client = self.get_reqclient();
 
-        Ok(req_client
-            .request(method, url)
-            .basic_auth(username, Some(password)))
+        Ok(auth.apply(req_client.request(method, url)))
+    }
+
+    async fn get_auth(&self) -> Result<Auth> {
+        let (host, oauth2_client) = match &self.profile.login_status {
+            LoginStatus::OAuth2 {
+                host,
+                client_id,
+                client_secret,
+                redirect_uri,
+                ..
+            } => (
+                host,
+                OAuth2Client {
+                    client_id: client_id.clone(),
+                    client_secret: client_secret.clone(),
+                    redirect_uri: redirect_uri.clone(),
+                },
+            ),
+            _ => return self.profile.get_auth(),
+        };
+
+        let hub = self.client_hub;
+        let name = &self.profile.name;
+        let token = hub.get_oauth2_token(name).ok_or(NotLoggedIn)?;
+        if !token.needs_refresh() {
+            return Ok(Auth::Bearer {
+                token: token.access_token,
+            });
+        }
+
+        let _guard = hub.oauth2_refresh_lock.lock().await;
+
+        // 待っている間に他のリクエストが更新しているかもしれない
+        let token = hub.get_oauth2_token(name).ok_or(NotLoggedIn)?;
+        if !token.needs_refresh() {
+            return Ok(Auth::Bearer {
+                token: token.access_token,
+            });
+        }
+
+        log::debug!("{}: refreshing OAuth2 token", name);
+        let res = login::oauth2_request_token(
+            self.get_reqclient(),
+            host,
+            &oauth2_client,
+            OAuth2Grant::RefreshToken(&token.refresh_token),
+        )
+        .await;
+        let res = match res {
+            Ok(res) => res,
+            Err(e) => match e.downcast_ref() {
+                Some(NotAuthorized) => return Err(self.not_authorized()),
+                _ => return Err(e),
+            },
+        };
+
+        let token = OAuth2Token::new(res.access_token, res.refresh_token, res.expires_in);
+        hub.oauth2_tokens
+            .lock()
+            .map_err(|_| LockError)?
+            .insert(name.clone(), token.clone());
+
+        if let Some(ref toml_path) = hub.toml_path {
+            readwrite::save_client_hub_to_toml(hub, toml_path)?;
+        }
+
+        Ok(Auth::Bearer {
+            token: token.access_token,
+        })
+    }
+
+    pub fn get_profile(&self) -> &Profile {
+        self.profile
+    }
+
+    // 401を受け取ったときに呼ぶ。profileに再ログインが必要な印を付けてNotAuthorizedを返す
+    pub fn not_authorized(&self) -> anyhow::Error {
+        if let Err(e) = self.client_hub.mark_relogin_required(&self.profile.name) {
+            log::error!("{:?}", e);
+        }
+        NotAuthorized.into()
     }
 
     pub fn get_root_prefix(&self) -> Result<String> {
@@ -318,23 +687,63 @@ impl<'a> ClientMut<'a> {
     pub fn get_mut_profile(&mut self) -> &mut Profile {
         self.client_hub.get_mut_profile(&self.profile_name).unwrap()
     }
+
+    pub fn get_reqclient(&self) -> &reqwest::Client {
+        self.client_hub
+            .get_profile_reqclient(&self.profile_name)
+            .unwrap_or(&self.client_hub.req_client)
+    }
 }
 
 #[derive(Debug)]
 pub struct LocalInfo {
     excludes: ExcludeList,
+    folder_pairs: Vec<FolderPair>,
 }
 
 use reqwest::header;
 
 impl LocalInfo {
     pub fn new(excludes: ExcludeList) -> Self {
-        Self { excludes }
+        Self {
+            excludes,
+            folder_pairs: Vec::new(),
+        }
     }
 
     pub fn get_exclude_list(&self) -> &ExcludeList {
         &self.excludes
     }
+
+    pub fn get_folder_pairs(&self) -> &[FolderPair] {
+        &self.folder_pairs
+    }
+
+    pub fn get_folder_pair(&self, name: &str) -> Option<&FolderPair> {
+        self.folder_pairs.iter().find(|p| p.name == name)
+    }
+
+    pub fn get_folder_pair_mut(&mut self, name: &str) -> Option<&mut FolderPair> {
+        self.folder_pairs.iter_mut().find(|p| p.name == name)
+    }
+
+    pub fn add_folder_pair(&mut self, folder_pair: FolderPair) -> Result<()> {
+        if self.get_folder_pair(&folder_pair.name).is_some() {
+            return Err(anyhow!("folder pair already exists: {}", folder_pair.name));
+        }
+        self.folder_pairs.push(folder_pair);
+        Ok(())
+    }
+
+    pub fn remove_folder_pair(&mut self, name: &str) -> Option<FolderPair> {
+        let index = self.folder_pairs.iter().position(|p| p.name == name)?;
+        Some(self.folder_pairs.remove(index))
+    }
+
+    // pair 自身の除外設定が無ければ全体のものを使う
+    pub fn get_pair_exclude_list<'a>(&'a self, folder_pair: &'a FolderPair) -> &'a ExcludeList {
+        folder_pair.get_exclude_list(&self.excludes)
+    }
 }
 
 /*
@@ -375,34 +784,43 @@ use globset::{Glob, GlobMatcher};
 
 #[derive(Debug, Clone, Default)]
 pub struct ExcludePaths {
-    blacks: Vec<GlobMatcher>,
+    // (元のパターン, matcher)
+    blacks: Vec<(PathBuf, GlobMatcher)>,
     pub(crate) original_blacks: Vec<PathBuf>,
-    whites: Vec<GlobMatcher>,
+    whites: Vec<(PathBuf, GlobMatcher)>,
     pub(crate) original_whites: Vec<PathBuf>,
 }
 
+fn compile_globs(paths: &[PathBuf]) -> Result<Vec<(PathBuf, GlobMatcher)>> {
+    paths
+        .iter()
+        .map(|p| {
+            let g = Glob::new(&p.to_string_lossy())?;
+            Ok((p.clone(), g.compile_matcher()))
+        })
+        .collect()
+}
+
 impl ExcludePaths {
-    pub fn new(original_blacks: Vec<PathBuf>, original_whites: Vec<PathBuf>) -> Self {
-        let blacks = original_blacks
-            .iter()
-            .filter_map(|p| {
-                let s = p.to_string_lossy();
-                Glob::new(&s).ok().map(|g| g.compile_matcher())
-            })
-            .collect();
-        let whites = original_whites
-            .iter()
-            .filter_map(|p| {
-                let s = p.to_string_lossy();
-                Glob::new(&s).ok().map(|g| g.compile_matcher())
-            })
-            .collect();
-        Self {
+    // 不正なパターンがあれば全て InvalidExcludePatterns にまとめて返す
+    pub fn new(original_blacks: Vec<PathBuf>, original_whites: Vec<PathBuf>) -> Result<Self> {
+        let errors = lint_patterns(
+            "excludes",
+            &original_blacks,
+            &original_whites,
+            &[],
+            &[],
+            &[],
+        );
+        if !errors.is_empty() {
+            return Err(InvalidExcludePatterns(errors).into());
+        }
+        Ok(Self {
+            blacks: compile_globs(&original_blacks)?,
             original_blacks,
-            blacks,
+            whites: compile_globs(&original_whites)?,
             original_whites,
-            whites,
-        }
+        })
     }
 }
 
@@ -412,83 +830,443 @@ pub struct ExcludeRegexes {
     pub(crate) original_blacks: Vec<String>,
     whites: Vec<Regex>,
     pub(crate) original_whites: Vec<String>,
+    // (名前, 規則)。BUILTIN_RULES から選んだもの
+    defaults: Vec<(String, Regex)>,
+    pub(crate) default_rules: Vec<String>,
+}
+
+/*
+名前付きの組み込み規則。パスの要素ごとに判定する。
+設定ファイルの default_rules に名前を並べて使い、省略すると DEFAULT_RULE_NAMES になる。
+空にすれば組み込み規則は何も使わない。
+*/
+pub const BUILTIN_RULES: &[(&str, &str)] = &[
+    // ".git", ".env" など
+    ("hidden", r"^\."),
+    // "~foo" など
+    ("tilde", r"^~"),
+    ("tmp", r"\.tmp$"),
+    ("thumbs_db", r"^Thumbs\.db$"),
+    ("ds_store", r"^\.DS_Store$"),
+    // Office が開いている間に作る "~$foo.docx"
+    ("office_lock", r"^~\$"),
+];
+
+pub const DEFAULT_RULE_NAMES: &[&str] = &["hidden", "tilde"];
+
+pub fn default_rule_names() -> Vec<String> {
+    DEFAULT_RULE_NAMES.iter().map(|s| s.to_string()).collect()
+}
+
+fn builtin_rule(name: &str) -> Option<&'static str> {
+    BUILTIN_RULES
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, rule)| *rule)
+}
+
+fn compile_regexes(regexes: &[String]) -> Result<Vec<Regex>> {
+    Ok(regexes
+        .iter()
+        .map(|s| Regex::new(s))
+        .collect::<std::result::Result<_, _>>()?)
 }
 
 impl ExcludeRegexes {
-    pub fn new(original_blacks: Vec<String>, original_whites: Vec<String>) -> Self {
-        let mut blacks = original_blacks
-            .iter()
-            .filter_map(|s| Regex::new(s).ok())
-            .collect::<Vec<_>>();
-        blacks.push(Regex::new(r"^\.").unwrap());
-        blacks.push(Regex::new(r"^~").unwrap());
-        let whites = original_whites
+    pub fn new(original_blacks: Vec<String>, original_whites: Vec<String>) -> Result<Self> {
+        Self::with_default_rules(original_blacks, original_whites, default_rule_names())
+    }
+
+    // 不正な正規表現や知らない名前の規則があれば全て InvalidExcludePatterns にまとめて返す
+    pub fn with_default_rules(
+        original_blacks: Vec<String>,
+        original_whites: Vec<String>,
+        default_rules: Vec<String>,
+    ) -> Result<Self> {
+        let errors = lint_patterns(
+            "excludes",
+            &[],
+            &[],
+            &original_blacks,
+            &original_whites,
+            &default_rules,
+        );
+        if !errors.is_empty() {
+            return Err(InvalidExcludePatterns(errors).into());
+        }
+        let defaults = default_rules
             .iter()
-            .filter_map(|s| Regex::new(s).ok())
-            .collect::<Vec<_>>();
-        Self {
-            blacks,
+            .filter_map(|name| builtin_rule(name).map(|rule| (name.clone(), rule)))
+            .map(|(name, rule)| Ok((name, Regex::new(rule)?)))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            blacks: compile_regexes(&original_blacks)?,
             original_blacks,
-            whites,
+            whites: compile_regexes(&original_whites)?,
             original_whites,
+            defaults,
+            default_rules,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatternKind {
+    BlackPath,
+    WhitePath,
+    BlackRegex,
+    WhiteRegex,
+    DefaultRule,
+    OlderThan,
+    NewerThan,
+    IgnoreFile,
+}
+
+impl PatternKind {
+    // 設定ファイルでのキー名
+    fn key(&self) -> &'static str {
+        match self {
+            PatternKind::BlackPath => "blackpaths",
+            PatternKind::WhitePath => "whitepaths",
+            PatternKind::BlackRegex => "blackregexes",
+            PatternKind::WhiteRegex => "whiteregexes",
+            PatternKind::DefaultRule => "default_rules",
+            PatternKind::OlderThan => "older_than",
+            PatternKind::NewerThan => "newer_than",
+            PatternKind::IgnoreFile => IGNORE_FILE_NAME,
+        }
+    }
+}
+
+// 解釈できなかった除外パターン
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatternError {
+    // 設定ファイルや .ncsyncignore のパス。コードから作ったものなら "excludes"
+    pub location: String,
+    pub kind: PatternKind,
+    // ファイル中の (行, 列)。どちらも1始まり。ファイルから読んだものでなければ None
+    pub position: Option<(usize, usize)>,
+    pub pattern: String,
+    pub message: String,
+}
+
+impl Display for PatternError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.position, self.kind) {
+            (Some((line, column)), PatternKind::IgnoreFile) => {
+                write!(f, "{}:{}:{}:", self.location, line, column)?
+            }
+            (Some((line, column)), kind) => {
+                write!(f, "{}:{}:{}: {}", self.location, line, column, kind.key())?
+            }
+            (None, kind) => write!(f, "{}.{}", self.location, kind.key())?,
+        }
+        write!(f, " {:?}: {}", self.pattern, self.message)
+    }
+}
+
+// パターン1つを検査する。Err は理由
+pub fn check_pattern(kind: PatternKind, pattern: &str) -> std::result::Result<(), String> {
+    match kind {
+        PatternKind::BlackPath | PatternKind::WhitePath | PatternKind::IgnoreFile => {
+            Glob::new(pattern)
+                .map(|_| ())
+                .map_err(|e| e.kind().to_string())
+        }
+        PatternKind::BlackRegex | PatternKind::WhiteRegex => {
+            Regex::new(pattern).map(|_| ()).map_err(|e| e.to_string())
+        }
+        PatternKind::DefaultRule => match builtin_rule(pattern) {
+            Some(_) => Ok(()),
+            None => {
+                let names = BUILTIN_RULES.iter().map(|(n, _)| *n).collect::<Vec<_>>();
+                Err(format!("unknown rule. available: {}", names.join(", ")))
+            }
+        },
+        PatternKind::OlderThan | PatternKind::NewerThan => parse_exclude_date(pattern).map(|_| ()),
+    }
+}
+
+// コードから渡された除外パターンを全て検査する。
+// 設定ファイルのものは readwrite の lint が位置付きで検査する
+pub fn lint_patterns(
+    location: &str,
+    blackpaths: &[PathBuf],
+    whitepaths: &[PathBuf],
+    blackregexes: &[String],
+    whiteregexes: &[String],
+    default_rules: &[String],
+) -> Vec<PatternError> {
+    let paths = [
+        (PatternKind::BlackPath, blackpaths),
+        (PatternKind::WhitePath, whitepaths),
+    ];
+    let paths = paths.iter().flat_map(|(kind, v)| {
+        v.iter()
+            .map(move |p| (*kind, p.to_string_lossy().to_string()))
+    });
+    let strings = [
+        (PatternKind::BlackRegex, blackregexes),
+        (PatternKind::WhiteRegex, whiteregexes),
+        (PatternKind::DefaultRule, default_rules),
+    ];
+    let strings = strings
+        .iter()
+        .flat_map(|(kind, v)| v.iter().map(move |s| (*kind, s.clone())));
+
+    paths
+        .chain(strings)
+        .filter_map(|(kind, pattern)| {
+            let message = check_pattern(kind, &pattern).err()?;
+            Some(PatternError {
+                location: location.to_string(),
+                kind,
+                position: None,
+                pattern,
+                message,
+            })
+        })
+        .collect()
+}
+
+// どの規則で judge の結果が決まったか
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExcludeRule {
+    WhitePath(PathBuf),
+    BlackPath(PathBuf),
+    IgnoreFile(IgnoreMatch),
+    WhiteRegex(String),
+    BlackRegex(String),
+    // BUILTIN_RULES の名前
+    DefaultRule(String),
+    // 以下はファイルの大きさと更新日時による規則
+    MaxSize(u64),
+    MinSize(u64),
+    OlderThan(DateTime<Local>),
+    NewerThan(DateTime<Local>),
+}
+
+// ファイルの大きさと更新日時で除外する。ディレクトリには使わない
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExcludeAttrs {
+    // byte。これより大きいものを除外する
+    pub max_size: Option<u64>,
+    // byte。これより小さいものを除外する
+    pub min_size: Option<u64>,
+    // これより前に更新されたものを除外する
+    pub older_than: Option<DateTime<Local>>,
+    // これより後に更新されたものを除外する
+    pub newer_than: Option<DateTime<Local>>,
+}
+
+impl ExcludeAttrs {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    fn check(&self, size: u64, last_modified: DateTime<Local>) -> Option<ExcludeRule> {
+        if let Some(max) = self.max_size.filter(|max| size > *max) {
+            return Some(ExcludeRule::MaxSize(max));
+        }
+        if let Some(min) = self.min_size.filter(|min| size < *min) {
+            return Some(ExcludeRule::MinSize(min));
+        }
+        if let Some(date) = self.older_than.filter(|date| last_modified < *date) {
+            return Some(ExcludeRule::OlderThan(date));
         }
+        if let Some(date) = self.newer_than.filter(|date| last_modified > *date) {
+            return Some(ExcludeRule::NewerThan(date));
+        }
+        None
     }
 }
 
+// "2024-01-02T03:04:05+09:00" か "2024-01-02" (その日の0時、ローカル時刻)
+pub fn parse_exclude_date(s: &str) -> std::result::Result<DateTime<Local>, String> {
+    if let Ok(date) = DateTime::parse_from_rfc3339(s) {
+        return Ok(date.with_timezone(&Local));
+    }
+    let date = NaiveDate::parse_from_str(s, "%Y-%m-%d").map_err(|e| e.to_string())?;
+    Local
+        .from_local_datetime(&date.and_hms(0, 0, 0))
+        .single()
+        .ok_or_else(|| "ambiguous local time".to_string())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Explanation {
+    pub included: bool,
+    // None ならどの規則にも当たらなかった
+    pub rule: Option<ExcludeRule>,
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct ExcludeList {
     pub(crate) paths: ExcludePaths,
     pub(crate) regexes: ExcludeRegexes,
+    // 設定ファイルには書かず、同期するときに local_root から読む
+    pub(crate) ignore_files: Option<IgnoreFiles>,
+    pub(crate) attrs: ExcludeAttrs,
 }
 
 use std::path::Path;
 
+fn match_self_or_parent(g: &GlobMatcher, path: &Path) -> bool {
+    path.ancestors().any(|p| g.is_match(p))
+}
+
 impl ExcludeList {
+    // 不正なパターンがあれば全て InvalidExcludePatterns にまとめて返す
     pub fn new(
         blackpaths: Vec<PathBuf>,
         whitepaths: Vec<PathBuf>,
         blackregexes: Vec<String>,
         whiteregexes: Vec<String>,
-    ) -> Self {
-        Self {
-            paths: ExcludePaths::new(blackpaths, whitepaths),
-            regexes: ExcludeRegexes::new(blackregexes, whiteregexes),
+    ) -> Result<Self> {
+        let errors = lint_patterns(
+            "excludes",
+            &blackpaths,
+            &whitepaths,
+            &blackregexes,
+            &whiteregexes,
+            &[],
+        );
+        if !errors.is_empty() {
+            return Err(InvalidExcludePatterns(errors).into());
+        }
+        Ok(Self {
+            paths: ExcludePaths::new(blackpaths, whitepaths)?,
+            regexes: ExcludeRegexes::new(blackregexes, whiteregexes)?,
+            ignore_files: None,
+            attrs: ExcludeAttrs::default(),
+        })
+    }
+
+    // 組み込み規則を BUILTIN_RULES の名前で選び直す
+    pub fn with_default_rules(mut self, default_rules: Vec<String>) -> Result<Self> {
+        let regexes = std::mem::take(&mut self.regexes);
+        self.regexes = ExcludeRegexes::with_default_rules(
+            regexes.original_blacks,
+            regexes.original_whites,
+            default_rules,
+        )?;
+        Ok(self)
+    }
+
+    pub fn with_attrs(mut self, attrs: ExcludeAttrs) -> Self {
+        self.attrs = attrs;
+        self
+    }
+
+    pub fn get_attrs(&self) -> &ExcludeAttrs {
+        &self.attrs
+    }
+
+    // local_root 以下の .ncsyncignore も判定に使う
+    pub fn with_ignore_files(mut self, ignore_files: IgnoreFiles) -> Self {
+        self.ignore_files = Some(ignore_files);
+        self
+    }
+
+    // folder pair の local_root から .ncsyncignore を読み込んだもの。除外されるディレクトリの中は読まない
+    pub fn load_ignore_files(&self, local_root: impl AsRef<Path>) -> Result<Self> {
+        let ignore_files = IgnoreFiles::load_with(local_root, |ignore_files, sync_path| {
+            self.explain_with(sync_path, true, Some(ignore_files))
+                .included
+        })?;
+        Ok(self.clone().with_ignore_files(ignore_files))
+    }
+
+    // .ncsyncignore の読めなかった行など
+    pub fn lint(&self, location: &str) -> Vec<PatternError> {
+        let mut errors = lint_patterns(
+            location,
+            &self.paths.original_blacks,
+            &self.paths.original_whites,
+            &self.regexes.original_blacks,
+            &self.regexes.original_whites,
+            &self.regexes.default_rules,
+        );
+        if let Some(ignore_files) = self.ignore_files.as_ref() {
+            errors.extend(ignore_files.lint().iter().cloned());
         }
+        errors
     }
 
     pub fn judge(&self, p: impl AsRef<Path>) -> bool {
-        let path = p.as_ref();
+        self.explain(p).included
+    }
 
-        // path white > path black > regex white > regex black
+    pub fn judge_dir(&self, p: impl AsRef<Path>) -> bool {
+        self.explain_dir(p).included
+    }
 
-        for g in &self.paths.whites {
-            if g.is_match(path) {
-                return true;
-            }
+    // ファイルについてはパスに加えて大きさと更新日時でも判定する
+    pub fn judge_file(
+        &self,
+        p: impl AsRef<Path>,
+        size: u64,
+        last_modified: DateTime<Local>,
+    ) -> bool {
+        self.explain_file(p, size, last_modified).included
+    }
 
-            let mut p = path;
-            while let Some(parent) = p.parent() {
-                if g.is_match(parent) {
-                    return true;
-                }
+    pub fn explain_file(
+        &self,
+        p: impl AsRef<Path>,
+        size: u64,
+        last_modified: DateTime<Local>,
+    ) -> Explanation {
+        let explanation = self.explain(p);
+        if !explanation.included {
+            return explanation;
+        }
+        match self.attrs.check(size, last_modified) {
+            Some(rule) => Explanation {
+                included: false,
+                rule: Some(rule),
+            },
+            None => explanation,
+        }
+    }
+
+    // パスだけで判定する。大きさの分からないファイルなど。ディレクトリは explain_dir
+    pub fn explain(&self, p: impl AsRef<Path>) -> Explanation {
+        self.explain_with(p.as_ref(), false, self.ignore_files.as_ref())
+    }
+
+    // .ncsyncignore の "dir/" のような行はディレクトリにだけ当たる
+    pub fn explain_dir(&self, p: impl AsRef<Path>) -> Explanation {
+        self.explain_with(p.as_ref(), true, self.ignore_files.as_ref())
+    }
 
-                p = parent;
+    fn explain_with(
+        &self,
+        path: &Path,
+        is_dir: bool,
+        ignore_files: Option<&IgnoreFiles>,
+    ) -> Explanation {
+        // path white > path black > .ncsyncignore > regex white > regex black > 組み込み規則
+
+        let decided = |included, rule| Explanation {
+            included,
+            rule: Some(rule),
+        };
+
+        for (original, g) in self.paths.whites.iter() {
+            if match_self_or_parent(g, path) {
+                return decided(true, ExcludeRule::WhitePath(original.clone()));
             }
         }
 
-        for g in &self.paths.blacks {
-            if g.is_match(path) {
-                return false;
+        for (original, g) in self.paths.blacks.iter() {
+            if match_self_or_parent(g, path) {
+                return decided(false, ExcludeRule::BlackPath(original.clone()));
             }
+        }
 
-            let mut p = path;
-            while let Some(parent) = p.parent() {
-                if g.is_match(parent) {
-                    return false;
-                }
-
-                p = parent;
-            }
+        if let Some(m) = ignore_files.and_then(|i| i.matched(path, is_dir)) {
+            return decided(m.whitelist, ExcludeRule::IgnoreFile(m));
         }
 
         'compcheck: for c in path.components() {
@@ -502,12 +1280,30 @@ impl ExcludeList {
 
             for r in self.regexes.blacks.iter() {
                 if r.is_match(&s) {
-                    return false;
+                    return decided(false, ExcludeRule::BlackRegex(r.as_str().to_string()));
+                }
+            }
+
+            // .ncsyncignore は他の端末でも同じように除外されるよう、組み込み規則では除外しない
+            if s == IGNORE_FILE_NAME {
+                continue;
+            }
+            for (name, r) in self.regexes.defaults.iter() {
+                if r.is_match(&s) {
+                    return decided(false, ExcludeRule::DefaultRule(name.clone()));
                 }
             }
         }
 
-        true
+        // regex white はそれだけでは結果を決めないので、当たったものを返す
+        let white = path.components().find_map(|c| {
+            let s = c.as_os_str().to_string_lossy();
+            self.regexes.whites.iter().find(|r| r.is_match(&s))
+        });
+        Explanation {
+            included: true,
+            rule: white.map(|r| ExcludeRule::WhiteRegex(r.as_str().to_string())),
+        }
     }
 }
 
@@ -529,3 +1325,34 @@ pub struct ContextSetting {
     pub curdir_setting: CurDirSetting,
     pub proxy: Option<String>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relogin_notice_test() {
+        let mut hub = ClientHub::load_with_credential_store(
+            "test".to_string(),
+            CredentialBackend::Plaintext,
+            CredentialBackend::Plaintext.open().unwrap(),
+        )
+        .unwrap();
+
+        // 読み込み時に印が付いた profile も、後から subscribe した側に届く
+        hub.mark_relogin_required("p").unwrap();
+        let mut relogin = hub.subscribe_relogin();
+        hub.mark_relogin_required("p").unwrap();
+        assert_eq!(relogin.try_recv().unwrap(), "p");
+        assert!(relogin.try_recv().is_err());
+
+        // 再ログインに失敗して印が残ったままでも、次の 401 で再び届く
+        let mut relogin = hub.subscribe_relogin();
+        hub.mark_relogin_required("p").unwrap();
+        assert_eq!(relogin.try_recv().unwrap(), "p");
+        assert!(hub.is_relogin_required("p"));
+
+        hub.clear_relogin_required("p").unwrap();
+        assert!(!hub.is_relogin_required("p"));
+    }
+}
@@ -0,0 +1,43 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+// 期限切れ直前のtokenでリクエストしないよう、この秒数だけ早めに更新する
+const REFRESH_MARGIN_SECS: i64 = 60;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OAuth2Token {
+    pub access_token: String,
+    pub refresh_token: String,
+    // unix time (秒)
+    pub expires_at: i64,
+}
+
+impl OAuth2Token {
+    pub fn new(access_token: String, refresh_token: String, expires_in: i64) -> Self {
+        Self {
+            access_token,
+            refresh_token,
+            expires_at: Utc::now().timestamp() + expires_in,
+        }
+    }
+
+    pub fn needs_refresh(&self) -> bool {
+        Utc::now().timestamp() + REFRESH_MARGIN_SECS >= self.expires_at
+    }
+}
+
+// リクエストへの認証情報の付け方。basic auth と bearer token の両方をここで扱う
+#[derive(Debug, Clone)]
+pub(crate) enum Auth {
+    Basic { username: String, password: String },
+    Bearer { token: String },
+}
+
+impl Auth {
+    pub(crate) fn apply(&self, builder: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match self {
+            Auth::Basic { username, password } => builder.basic_auth(username, Some(password)),
+            Auth::Bearer { token } => builder.bearer_auth(token),
+        }
+    }
+}
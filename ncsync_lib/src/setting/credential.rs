@@ -0,0 +1,319 @@
+use crate::errors::NcsError::*;
+use anyhow::{Context, Result};
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt::Debug;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+pub const PASSPHRASE_ENV: &str = "NCSYNC_CREDENTIAL_PASSPHRASE";
+const KEYRING_SERVICE: &str = "ncsync";
+// Keyring が使えないときに profiles.toml と同じディレクトリに作る
+pub const DEFAULT_ENCRYPTED_FILE_NAME: &str = "credentials.enc";
+
+const ENCRYPTED_FILE_MAGIC: &[u8] = b"NCSYNC_CRED_V1\n";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+// app password の保存先。profiles.toml の [credential_backend] に対応する
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(tag = "type", content = "info")]
+pub enum CredentialBackend {
+    // Linux では Secret Service
+    #[default]
+    Keyring,
+    EncryptedFile {
+        path: PathBuf,
+    },
+    // profiles.toml に平文で書く。明示的に選んだときだけ使う
+    Plaintext,
+}
+
+impl CredentialBackend {
+    // backend が指定されていないときに使うもの。
+    // Keyring が使えない環境 (Secret Service の無いサーバーなど) では、passphrase があれば
+    // toml_path と同じディレクトリの暗号化ファイルにする。どちらも使えなければエラー
+    pub fn detect(toml_path: Option<&Path>) -> Result<Self> {
+        let e = match KeyringStore::check() {
+            Ok(()) => return Ok(Self::Keyring),
+            Err(e) => e,
+        };
+        match toml_path {
+            Some(toml_path) if std::env::var_os(PASSPHRASE_ENV).is_some() => {
+                let path = toml_path.with_file_name(DEFAULT_ENCRYPTED_FILE_NAME);
+                log::warn!("{}. Using {} instead.", e, path.display());
+                Ok(Self::EncryptedFile { path })
+            }
+            _ => Err(e),
+        }
+    }
+
+    pub fn open(&self) -> Result<Box<dyn CredentialStore>> {
+        let store: Box<dyn CredentialStore> = match self {
+            Self::Keyring => Box::new(KeyringStore),
+            Self::EncryptedFile { path } => {
+                let passphrase = std::env::var(PASSPHRASE_ENV).with_context(|| {
+                    format!("Please set {} to unlock {}", PASSPHRASE_ENV, path.display())
+                })?;
+                Box::new(EncryptedFileStore::new(path.clone(), passphrase))
+            }
+            Self::Plaintext => Box::new(PlaintextStore),
+        };
+        Ok(store)
+    }
+}
+
+pub trait CredentialStore: Debug + Send + Sync {
+    fn get(&self, profile_name: &str, username: &str) -> Result<Option<String>>;
+
+    fn set(&self, profile_name: &str, username: &str, password: &str) -> Result<()>;
+
+    fn delete(&self, profile_name: &str, username: &str) -> Result<()>;
+
+    // trueなら呼び出し側が profiles.toml に password を書き込む
+    fn is_plaintext(&self) -> bool {
+        false
+    }
+}
+
+fn account_name(profile_name: &str, username: &str) -> String {
+    format!("{}/{}", profile_name, username)
+}
+
+#[derive(Debug)]
+pub struct PlaintextStore;
+
+impl CredentialStore for PlaintextStore {
+    fn get(&self, _profile_name: &str, _username: &str) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    fn set(&self, _profile_name: &str, _username: &str, _password: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn delete(&self, _profile_name: &str, _username: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn is_plaintext(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Debug)]
+pub struct KeyringStore;
+
+// Secret Service などに繋がらないときは設定の直し方が分かるエラーにする
+fn keyring_error(e: keyring::Error) -> anyhow::Error {
+    match e {
+        keyring::Error::PlatformFailure(_) | keyring::Error::NoStorageAccess(_) => {
+            KeyringUnavailable(e.to_string()).into()
+        }
+        e => e.into(),
+    }
+}
+
+impl KeyringStore {
+    fn entry(profile_name: &str, username: &str) -> Result<keyring::Entry> {
+        keyring::Entry::new(KEYRING_SERVICE, &account_name(profile_name, username))
+            .map_err(keyring_error)
+    }
+
+    // 無いものを読んでみて、Keyring に繋がるか確かめる
+    fn check() -> Result<()> {
+        KeyringStore.get("", "").map(|_| ())
+    }
+}
+
+impl CredentialStore for KeyringStore {
+    fn get(&self, profile_name: &str, username: &str) -> Result<Option<String>> {
+        match Self::entry(profile_name, username)?.get_password() {
+            Ok(password) => Ok(Some(password)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(e) => Err(keyring_error(e)),
+        }
+    }
+
+    fn set(&self, profile_name: &str, username: &str, password: &str) -> Result<()> {
+        Self::entry(profile_name, username)?
+            .set_password(password)
+            .map_err(keyring_error)
+    }
+
+    fn delete(&self, profile_name: &str, username: &str) -> Result<()> {
+        match Self::entry(profile_name, username)?.delete_password() {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(e) => Err(keyring_error(e)),
+        }
+    }
+}
+
+// magic + salt + nonce + ChaCha20Poly1305(json)
+// 鍵は passphrase から Argon2 で導出する
+pub struct EncryptedFileStore {
+    path: PathBuf,
+    passphrase: String,
+    // 最後に使った (salt, 鍵)。Argon2 は遅いので salt が同じ間は導出し直さない
+    key: Mutex<Option<([u8; SALT_LEN], Key)>>,
+}
+
+impl Debug for EncryptedFileStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncryptedFileStore")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+impl EncryptedFileStore {
+    pub fn new(path: PathBuf, passphrase: String) -> Self {
+        Self {
+            path,
+            passphrase,
+            key: Mutex::new(None),
+        }
+    }
+
+    fn derive_key(&self, salt: &[u8; SALT_LEN]) -> Result<Key> {
+        let mut cached = self.key.lock().map_err(|_| LockError)?;
+        if let Some((cached_salt, key)) = cached.as_ref() {
+            if cached_salt == salt {
+                return Ok(*key);
+            }
+        }
+
+        let mut key = Key::default();
+        Argon2::default()
+            .hash_password_into(self.passphrase.as_bytes(), salt, &mut key)
+            .map_err(|e| anyhow!("Failed to derive key: {}", e))?;
+        *cached = Some((*salt, key));
+        Ok(key)
+    }
+
+    // 書き込むときは読んだときの salt を使い回す。nonce は毎回作り直す
+    fn salt(&self) -> Result<[u8; SALT_LEN]> {
+        if let Some((salt, _)) = self.key.lock().map_err(|_| LockError)?.as_ref() {
+            return Ok(*salt);
+        }
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        Ok(salt)
+    }
+
+    fn load(&self) -> Result<HashMap<String, String>> {
+        let buf = match fs::read(&self.path) {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let body = buf
+            .strip_prefix(ENCRYPTED_FILE_MAGIC)
+            .context("Invalid credential file")?;
+        if body.len() < SALT_LEN + NONCE_LEN {
+            return Err(anyhow!("Invalid credential file"));
+        }
+        let (salt, body) = body.split_at(SALT_LEN);
+        let (nonce, ciphertext) = body.split_at(NONCE_LEN);
+        let salt = salt.try_into().context("Invalid credential file")?;
+
+        let cipher = ChaCha20Poly1305::new(&self.derive_key(salt)?);
+        let plain = cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow!("Could not decrypt credentials. Wrong passphrase?"))?;
+
+        Ok(serde_json::from_slice(&plain)?)
+    }
+
+    fn save(&self, credentials: &HashMap<String, String>) -> Result<()> {
+        let salt = self.salt()?;
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+        let cipher = ChaCha20Poly1305::new(&self.derive_key(&salt)?);
+        let plain = serde_json::to_vec(credentials)?;
+        let ciphertext = cipher
+            .encrypt(&nonce, plain.as_ref())
+            .map_err(|_| anyhow!("Could not encrypt credentials"))?;
+
+        let mut buf = ENCRYPTED_FILE_MAGIC.to_vec();
+        buf.extend_from_slice(&salt);
+        buf.extend_from_slice(&nonce);
+        buf.extend_from_slice(&ciphertext);
+        fs::write(&self.path, buf)?;
+        Ok(())
+    }
+}
+
+impl CredentialStore for EncryptedFileStore {
+    fn get(&self, profile_name: &str, username: &str) -> Result<Option<String>> {
+        let mut credentials = self.load()?;
+        Ok(credentials.remove(&account_name(profile_name, username)))
+    }
+
+    fn set(&self, profile_name: &str, username: &str, password: &str) -> Result<()> {
+        let mut credentials = self.load()?;
+        let account = account_name(profile_name, username);
+        if credentials.get(&account).map(|p| p.as_str()) == Some(password) {
+            return Ok(());
+        }
+        credentials.insert(account, password.to_string());
+        self.save(&credentials)
+    }
+
+    fn delete(&self, profile_name: &str, username: &str) -> Result<()> {
+        let mut credentials = self.load()?;
+        if credentials
+            .remove(&account_name(profile_name, username))
+            .is_some()
+        {
+            self.save(&credentials)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encrypted_file_roundtrip_test() {
+        let path = std::env::temp_dir().join(format!("ncsync_cred_{}", uuid::Uuid::new_v4()));
+        let store = EncryptedFileStore::new(path.clone(), "passphrase".to_string());
+
+        store.set("profile", "user", "app-password").unwrap();
+        assert_eq!(
+            store.get("profile", "user").unwrap().as_deref(),
+            Some("app-password")
+        );
+        assert!(!fs::read(&path)
+            .unwrap()
+            .windows(12)
+            .any(|w| w == b"app-password"));
+
+        // 同じ salt のまま書き直すので鍵は導出し直さない
+        let salt = fs::read(&path).unwrap()[ENCRYPTED_FILE_MAGIC.len()..][..SALT_LEN].to_vec();
+        store.set("profile", "other", "other-password").unwrap();
+        let cached = store.key.lock().unwrap().map(|(salt, _)| salt.to_vec());
+        assert_eq!(cached, Some(salt.clone()));
+        assert_eq!(
+            fs::read(&path).unwrap()[ENCRYPTED_FILE_MAGIC.len()..][..SALT_LEN].to_vec(),
+            salt
+        );
+
+        let wrong = EncryptedFileStore::new(path.clone(), "wrong".to_string());
+        assert!(wrong.get("profile", "user").is_err());
+
+        store.delete("profile", "user").unwrap();
+        assert_eq!(store.get("profile", "user").unwrap(), None);
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,146 @@
+use crate::setting::ExcludeList;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncDirection {
+    PullOnly,
+    PushOnly,
+    #[default]
+    Bidirectional,
+}
+
+impl SyncDirection {
+    pub fn pulls(&self) -> bool {
+        !matches!(self, SyncDirection::PushOnly)
+    }
+
+    pub fn pushes(&self) -> bool {
+        !matches!(self, SyncDirection::PullOnly)
+    }
+}
+
+// サーバーが受け付けない名前のファイルを push するときにどうするか
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InvalidNamePolicy {
+    // 上げずに SyncReport に載せる
+    #[default]
+    Skip,
+    // 手元のファイルを受け付けられる名前に変えてから上げる
+    Rename,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Schedule {
+    // daemon の既定の間隔
+    #[default]
+    Default,
+    // 明示的に同期を指示されたときだけ
+    Manual,
+    IntervalSecs(u64),
+}
+
+impl Schedule {
+    // None なら定期的には同期しない
+    pub fn interval(&self, default: Duration) -> Option<Duration> {
+        match self {
+            Schedule::Default => Some(default),
+            Schedule::Manual => None,
+            Schedule::IntervalSecs(secs) => Some(Duration::from_secs(*secs)),
+        }
+    }
+}
+
+// リモートのどのサブツリーを手元に置くか。パスは folder pair の root からの sync_path
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SelectiveSync {
+    // 空でなければこれらの下だけを同期する
+    pub include: Vec<PathBuf>,
+    // include より優先する
+    pub skip: Vec<PathBuf>,
+}
+
+impl SelectiveSync {
+    pub fn is_synced(&self, sync_path: impl AsRef<Path>) -> bool {
+        let path = sync_path.as_ref();
+
+        if self.skip.iter().any(|s| path.starts_with(s)) {
+            return false;
+        }
+
+        // include の親ディレクトリも辿れるように残す
+        self.include.is_empty()
+            || self
+                .include
+                .iter()
+                .any(|i| path.starts_with(i) || i.starts_with(path))
+    }
+}
+
+// localinfo.toml の [[folder_pairs]] に対応する。name は一意でなければならない
+#[derive(Debug, Clone)]
+pub struct FolderPair {
+    pub name: String,
+    pub profile: String,
+    pub local_root: PathBuf,
+    pub remote_root: String,
+    pub direction: SyncDirection,
+    pub schedule: Schedule,
+    // true なら pull で新しいファイルを placeholder として置き、必要になったら hydrate する
+    pub placeholders: bool,
+    pub invalid_names: InvalidNamePolicy,
+    pub selective: SelectiveSync,
+    // None なら LocalInfo 全体の除外設定を使う
+    pub excludes: Option<ExcludeList>,
+}
+
+impl FolderPair {
+    pub fn new(
+        name: impl Into<String>,
+        profile: impl Into<String>,
+        local_root: impl Into<PathBuf>,
+        remote_root: impl Into<String>,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            profile: profile.into(),
+            local_root: local_root.into(),
+            remote_root: remote_root.into(),
+            direction: SyncDirection::default(),
+            schedule: Schedule::default(),
+            placeholders: false,
+            invalid_names: InvalidNamePolicy::default(),
+            selective: SelectiveSync::default(),
+            excludes: None,
+        }
+    }
+
+    pub fn get_exclude_list<'a>(&'a self, default: &'a ExcludeList) -> &'a ExcludeList {
+        self.excludes.as_ref().unwrap_or(default)
+    }
+
+    pub(crate) fn remote_path(&self, sync_path: &Path) -> PathBuf {
+        Path::new(&self.remote_root).join(strip_root(sync_path))
+    }
+
+    pub(crate) fn local_path(&self, sync_path: &Path) -> PathBuf {
+        self.local_root.join(strip_root(sync_path))
+    }
+
+    pub(crate) fn sync_path_from_remote(&self, remote_path: &Path) -> Option<PathBuf> {
+        let relative = remote_path.strip_prefix(&self.remote_root).ok()?;
+        Some(Path::new("/").join(relative))
+    }
+}
+
+/*
+sync_path は folder pair の root からの相対パスを "/a/b" の形にしたもの。
+同期するときの ExcludeList, SelectiveSync はこの形で判定する。
+*/
+fn strip_root(sync_path: &Path) -> &Path {
+    sync_path.strip_prefix("/").unwrap_or(sync_path)
+}
@@ -0,0 +1,245 @@
+use crate::setting::{PatternError, PatternKind};
+use anyhow::Result;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// 同期するツリーのどこにでも置ける。書式は .gitignore と同じ
+pub const IGNORE_FILE_NAME: &str = ".ncsyncignore";
+
+// .ncsyncignore のどの行に当たったか
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IgnoreMatch {
+    pub file: PathBuf,
+    pub pattern: String,
+    // "!" で始まる行なら true
+    pub whitelist: bool,
+}
+
+// local_root 以下の .ncsyncignore をまとめたもの
+#[derive(Debug, Clone)]
+pub struct IgnoreFiles {
+    local_root: PathBuf,
+    // (置かれているディレクトリの sync_path, 中身)。深いものが後ろ
+    ignores: Vec<(PathBuf, Gitignore)>,
+    // 読めなかった行。その行は無視して続ける
+    errors: Vec<PatternError>,
+}
+
+fn collect_errors(file: &Path, line: usize, e: &ignore::Error, res: &mut Vec<PatternError>) {
+    let (pattern, message) = match e {
+        ignore::Error::Partial(errs) => {
+            for e in errs {
+                collect_errors(file, line, e, res);
+            }
+            return;
+        }
+        ignore::Error::WithLineNumber { line, err } => {
+            return collect_errors(file, *line as usize, err, res)
+        }
+        ignore::Error::WithPath { err, .. } | ignore::Error::WithDepth { err, .. } => {
+            return collect_errors(file, line, err, res)
+        }
+        ignore::Error::Glob { glob, err } => (glob.clone().unwrap_or_default(), err.clone()),
+        e => (String::new(), e.to_string()),
+    };
+    res.push(PatternError {
+        location: file.to_string_lossy().to_string(),
+        kind: PatternKind::IgnoreFile,
+        // gitignore の行は先頭から1つのパターン
+        position: Some((line, 1)),
+        pattern,
+        message,
+    });
+}
+
+fn strip_root(sync_path: &Path) -> &Path {
+    sync_path.strip_prefix("/").unwrap_or(sync_path)
+}
+
+impl IgnoreFiles {
+    pub fn load(local_root: impl AsRef<Path>) -> Result<Self> {
+        Self::load_with(local_root, |ignore_files, sync_path| {
+            ignore_files
+                .matched(sync_path, true)
+                .map_or(true, |m| m.whitelist)
+        })
+    }
+
+    // included が false を返したディレクトリの中は読まない。
+    // included には途中まで読んだ IgnoreFiles が渡るので、親の .ncsyncignore で除外されたものも判定できる
+    pub(crate) fn load_with(
+        local_root: impl AsRef<Path>,
+        included: impl Fn(&IgnoreFiles, &Path) -> bool,
+    ) -> Result<Self> {
+        let mut res = Self {
+            local_root: local_root.as_ref().to_path_buf(),
+            ignores: Vec::new(),
+            errors: Vec::new(),
+        };
+        res.load_rec(Path::new("/"), &included)?;
+        res.ignores.sort_by_key(|(dir, _)| dir.components().count());
+
+        Ok(res)
+    }
+
+    // 読めないディレクトリは飛ばす。同期するときに改めてエラーになる
+    fn load_rec(
+        &mut self,
+        sync_dir: &Path,
+        included: &impl Fn(&IgnoreFiles, &Path) -> bool,
+    ) -> Result<()> {
+        let dir = self.local_root.join(strip_root(sync_dir));
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                log::warn!("could not read {}: {}", dir.display(), e);
+                return Ok(());
+            }
+        };
+
+        // 中のディレクトリを判定する前に、このディレクトリのものを読む
+        let file = dir.join(IGNORE_FILE_NAME);
+        if file.is_file() {
+            let mut builder = GitignoreBuilder::new(&dir);
+            if let Some(e) = builder.add(&file) {
+                log::warn!("{}: {}", file.display(), e);
+                collect_errors(&file, 0, &e, &mut self.errors);
+            }
+            self.ignores
+                .push((sync_dir.to_path_buf(), builder.build()?));
+        }
+
+        for entry in entries.flatten() {
+            match entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => (),
+                _ => continue,
+            }
+            let sync_path = sync_dir.join(entry.file_name());
+            if included(self, &sync_path) {
+                self.load_rec(&sync_path, included)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn lint(&self) -> &[PatternError] {
+        &self.errors
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ignores.is_empty()
+    }
+
+    // 1つのパスについて、近い .ncsyncignore から順に見て最初に当たった行
+    fn matched_one(&self, sync_path: &Path, is_dir: bool) -> Option<IgnoreMatch> {
+        let local = self.local_root.join(strip_root(sync_path));
+
+        for (dir, ignore) in self.ignores.iter().rev() {
+            if !sync_path.starts_with(dir) || sync_path == dir {
+                continue;
+            }
+            let (glob, whitelist) = match ignore.matched(&local, is_dir) {
+                Match::None => continue,
+                Match::Ignore(glob) => (glob, false),
+                Match::Whitelist(glob) => (glob, true),
+            };
+            return Some(IgnoreMatch {
+                file: glob.from().map(Path::to_path_buf).unwrap_or_else(|| {
+                    self.local_root.join(strip_root(dir)).join(IGNORE_FILE_NAME)
+                }),
+                pattern: glob.original().to_string(),
+                whitelist,
+            });
+        }
+
+        None
+    }
+
+    // sync_path に当たった行。親ディレクトリが除外されていれば中は "!" でも戻せない。
+    // リモートにしか無いものもあるので、ディレクトリかどうかは呼ぶ側が渡す
+    pub fn matched(&self, sync_path: impl AsRef<Path>, is_dir: bool) -> Option<IgnoreMatch> {
+        let sync_path = sync_path.as_ref();
+
+        let mut ancestors = sync_path.ancestors().collect::<Vec<_>>();
+        ancestors.reverse();
+        // "/" 自身は対象外
+        for dir in ancestors
+            .iter()
+            .skip(1)
+            .take(ancestors.len().saturating_sub(2))
+        {
+            if let Some(m) = self.matched_one(dir, true) {
+                if !m.whitelist {
+                    return Some(m);
+                }
+            }
+        }
+
+        self.matched_one(sync_path, is_dir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::setting::{ExcludeList, ExcludeRule};
+
+    #[test]
+    fn ignore_file_test() {
+        let root = std::env::temp_dir().join(format!("ncsync_ignore_{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(root.join("src/build")).unwrap();
+        fs::create_dir_all(root.join("build")).unwrap();
+        fs::write(
+            root.join(IGNORE_FILE_NAME),
+            "*.log\n!keep.log\n/build/\n**/tmp\ncache/\n",
+        )
+        .unwrap();
+        fs::write(root.join("src").join(IGNORE_FILE_NAME), "!*.log\n").unwrap();
+        // 除外されたディレクトリの中は読まない
+        fs::write(root.join("build").join(IGNORE_FILE_NAME), "[z-a]\n").unwrap();
+
+        let exclude_list = ExcludeList::new(vec![], vec![], vec![], vec![])
+            .unwrap()
+            .load_ignore_files(&root)
+            .unwrap();
+
+        assert!(!exclude_list.judge("/a.log"));
+        assert!(exclude_list.judge("/keep.log"));
+        // 深い .ncsyncignore が優先する
+        assert!(exclude_list.judge("/src/a.log"));
+        // 先頭の "/" があれば置かれた場所からのパスにだけ当たる
+        assert!(!exclude_list.judge("/build/a.txt"));
+        assert!(exclude_list.judge("/src/build"));
+        assert!(!exclude_list.judge("/src/x/tmp/a.txt"));
+        // "dir/" は手元に無くてもディレクトリにだけ当たる
+        assert!(!exclude_list.judge_dir("/remote/cache"));
+        assert!(exclude_list.judge("/remote/cache"));
+        assert!(exclude_list.lint("test").is_empty());
+        // 既定の規則も残る
+        assert!(!exclude_list.judge("/.git"));
+        // .ncsyncignore 自体は hidden でも除外せず、他の端末にも配る
+        assert!(exclude_list.judge(format!("/src/{}", IGNORE_FILE_NAME)));
+        assert_eq!(
+            exclude_list.explain("/.git").rule,
+            Some(ExcludeRule::DefaultRule("hidden".to_string()))
+        );
+        let without_defaults = exclude_list.clone().with_default_rules(vec![]).unwrap();
+        assert!(without_defaults.judge("/.gitlab-ci.yml"));
+
+        let explanation = exclude_list.explain("/build/a.txt");
+        assert!(!explanation.included);
+        assert_eq!(
+            explanation.rule,
+            Some(ExcludeRule::IgnoreFile(IgnoreMatch {
+                file: root.join(IGNORE_FILE_NAME),
+                pattern: "/build/".to_string(),
+                whitelist: false,
+            }))
+        );
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
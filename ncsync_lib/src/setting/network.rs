@@ -0,0 +1,249 @@
+use anyhow::{Context, Result};
+use reqwest::{Certificate, ClientBuilder, Identity, Proxy};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_POOL_IDLE_TIMEOUT_SECS: u64 = 90;
+
+// profiles.toml の [profiles.network] に対応する
+// tomlの都合上、テーブルになるtlsは最後に置くこと
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NetworkSetting {
+    // http://, https://, socks5://, socks5h:// のいずれか
+    pub proxy: Option<String>,
+    // 0 ならタイムアウトしない
+    #[serde(default = "default_connect_timeout_secs")]
+    pub connect_timeout_secs: u64,
+    // リクエスト全体 (レスポンスボディの受信を含む) のタイムアウト。0 ならタイムアウトしない
+    #[serde(default)]
+    pub request_timeout_secs: u64,
+    // 0 ならアイドル接続を期限切れにしない
+    #[serde(default = "default_pool_idle_timeout_secs")]
+    pub pool_idle_timeout_secs: u64,
+    pub pool_max_idle_per_host: Option<usize>,
+    // trueならALPNで交渉せずにHTTP/2を強制する。HTTP/2に対応していないサーバーには繋がらない
+    #[serde(default, alias = "http2_prior_knowledge")]
+    pub force_http2: bool,
+    #[serde(default)]
+    pub tls: TlsSetting,
+}
+
+fn default_connect_timeout_secs() -> u64 {
+    DEFAULT_CONNECT_TIMEOUT_SECS
+}
+
+fn default_pool_idle_timeout_secs() -> u64 {
+    DEFAULT_POOL_IDLE_TIMEOUT_SECS
+}
+
+impl Default for NetworkSetting {
+    fn default() -> Self {
+        Self {
+            proxy: None,
+            connect_timeout_secs: default_connect_timeout_secs(),
+            request_timeout_secs: 0,
+            pool_idle_timeout_secs: default_pool_idle_timeout_secs(),
+            pool_max_idle_per_host: None,
+            force_http2: false,
+            tls: TlsSetting::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TlsSetting {
+    // PEM or DER
+    #[serde(default)]
+    pub ca_certs: Vec<PathBuf>,
+    // PKCS#12 (.p12 / .pfx)
+    pub client_identity: Option<PathBuf>,
+    pub client_identity_password: Option<String>,
+    // 自己署名証明書のステージングサーバー向け。明示的に true にしたときだけ有効
+    #[serde(default)]
+    pub accept_invalid_certs: bool,
+}
+
+impl NetworkSetting {
+    pub fn with_proxy(proxy: Option<String>) -> Self {
+        Self {
+            proxy,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn apply(&self, builder: ClientBuilder) -> Result<ClientBuilder> {
+        let mut builder = builder;
+
+        if let Some(ref proxy) = self.proxy {
+            let proxy = Proxy::all(proxy).with_context(|| format!("Invalid proxy: {}", proxy))?;
+            builder = builder.proxy(proxy);
+        }
+
+        if let Some(timeout) = timeout_from_secs(self.connect_timeout_secs) {
+            builder = builder.connect_timeout(timeout);
+        }
+
+        if let Some(timeout) = timeout_from_secs(self.request_timeout_secs) {
+            builder = builder.timeout(timeout);
+        }
+
+        builder = builder.pool_idle_timeout(timeout_from_secs(self.pool_idle_timeout_secs));
+
+        if let Some(max) = self.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max);
+        }
+
+        if self.force_http2 {
+            builder = builder.http2_prior_knowledge();
+        }
+
+        self.tls.apply(builder)
+    }
+}
+
+// 0 は無効
+fn timeout_from_secs(secs: u64) -> Option<Duration> {
+    match secs {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    }
+}
+
+const PEM_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+const PEM_END: &str = "-----END CERTIFICATE-----";
+
+// PEM には証明書がいくつも入っていることがあるので1つずつに分ける。PEM でなければ None (DER)
+fn split_pem_certs(buf: &[u8]) -> Option<Vec<&[u8]>> {
+    let text = std::str::from_utf8(buf).ok()?;
+    let mut res = Vec::new();
+    let mut rest = text;
+    while let Some(begin) = rest.find(PEM_BEGIN) {
+        let block = &rest[begin..];
+        let end = block.find(PEM_END)? + PEM_END.len();
+        res.push(&block.as_bytes()[..end]);
+        rest = &block[end..];
+    }
+    if res.is_empty() {
+        None
+    } else {
+        Some(res)
+    }
+}
+
+fn read_ca_certs<C>(
+    path: &std::path::Path,
+    from_pem: impl Fn(&[u8]) -> std::result::Result<C, anyhow::Error>,
+    from_der: impl Fn(&[u8]) -> std::result::Result<C, anyhow::Error>,
+) -> Result<Vec<C>> {
+    let buf =
+        fs::read(path).with_context(|| format!("Could not read CA cert: {}", path.display()))?;
+    let certs = match split_pem_certs(&buf) {
+        Some(blocks) => blocks.into_iter().map(from_pem).collect(),
+        None => from_der(&buf).map(|cert| vec![cert]),
+    };
+    certs.with_context(|| format!("Invalid CA cert: {}", path.display()))
+}
+
+impl TlsSetting {
+    // reqwest を通さない接続 (notify_push の websocket) 用
+    pub(crate) fn native_tls_connector(&self) -> Result<native_tls::TlsConnector> {
+        let mut builder = native_tls::TlsConnector::builder();
+
+        for path in self.ca_certs.iter() {
+            let certs = read_ca_certs(
+                path,
+                |b| Ok(native_tls::Certificate::from_pem(b)?),
+                |b| Ok(native_tls::Certificate::from_der(b)?),
+            )?;
+            for cert in certs {
+                builder.add_root_certificate(cert);
+            }
+        }
+
+        if let Some(ref path) = self.client_identity {
+            let buf = fs::read(path)
+                .with_context(|| format!("Could not read client identity: {}", path.display()))?;
+            let password = self.client_identity_password.as_deref().unwrap_or("");
+            let identity = native_tls::Identity::from_pkcs12(&buf, password)
+                .with_context(|| format!("Invalid client identity: {}", path.display()))?;
+            builder.identity(identity);
+        }
+
+        if self.accept_invalid_certs {
+            builder.danger_accept_invalid_certs(true);
+        }
+
+        Ok(builder.build()?)
+    }
+
+    fn apply(&self, builder: ClientBuilder) -> Result<ClientBuilder> {
+        let mut builder = builder;
+
+        for path in self.ca_certs.iter() {
+            let certs = read_ca_certs(
+                path,
+                |b| Ok(Certificate::from_pem(b)?),
+                |b| Ok(Certificate::from_der(b)?),
+            )?;
+            for cert in certs {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+
+        if let Some(ref path) = self.client_identity {
+            let buf = fs::read(path)
+                .with_context(|| format!("Could not read client identity: {}", path.display()))?;
+            let password = self.client_identity_password.as_deref().unwrap_or("");
+            let identity = Identity::from_pkcs12_der(&buf, password)
+                .with_context(|| format!("Invalid client identity: {}", path.display()))?;
+            builder = builder.identity(identity);
+        }
+
+        if self.accept_invalid_certs {
+            log::warn!("TLS certificate verification is disabled.");
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+
+        Ok(builder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_pem_certs_test() {
+        let pem = format!(
+            "# root\n{}\nAAAA\n{}\n{}\nBBBB\n{}\n",
+            PEM_BEGIN, PEM_END, PEM_BEGIN, PEM_END
+        );
+        let blocks = split_pem_certs(pem.as_bytes()).unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(
+            blocks[1],
+            format!("{}\nBBBB\n{}", PEM_BEGIN, PEM_END).as_bytes()
+        );
+
+        assert_eq!(split_pem_certs(&[0x30, 0x82, 0x01]), None);
+    }
+
+    #[test]
+    fn timeout_test() {
+        let setting: NetworkSetting = toml::from_str("").unwrap();
+        assert_eq!(setting.request_timeout_secs, 0);
+        assert_eq!(setting.connect_timeout_secs, DEFAULT_CONNECT_TIMEOUT_SECS);
+        assert!(!setting.force_http2);
+
+        let setting: NetworkSetting =
+            toml::from_str("request_timeout_secs = 120\nhttp2_prior_knowledge = true").unwrap();
+        assert_eq!(setting.request_timeout_secs, 120);
+        assert!(setting.force_http2);
+
+        assert_eq!(timeout_from_secs(0), None);
+        assert_eq!(timeout_from_secs(120), Some(Duration::from_secs(120)));
+    }
+}
@@ -1,14 +1,230 @@
-use crate::setting::{ClientHub, ExcludeList, LocalInfo, LoginStatus};
-use anyhow::Result;
+use crate::errors::NcsError::*;
+use crate::setting::{
+    check_pattern, default_rule_names, parse_exclude_date, ClientHub, CredentialBackend,
+    CredentialStore, ExcludeAttrs, ExcludeList, FolderPair, InvalidNamePolicy, LocalInfo,
+    LoginStatus, NetworkSetting, OAuth2Token, PatternError, PatternKind, Schedule, SelectiveSync,
+    SyncDirection,
+};
+use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 use std::default::Default;
 use std::fs;
 use std::path::{Path, PathBuf};
+use toml::Spanned;
+use uuid::Uuid;
+
+// LoginStatusとほぼ同じだが、passwordはCredentialStoreがplaintextのときだけ書き込む
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", content = "info")]
+enum LoginStatusRaw {
+    NotYet,
+    Polling {
+        token: String,
+        end_point: String,
+    },
+    LoggedIn {
+        host: String,
+        username: String,
+        password: Option<String>,
+    },
+    OAuth2 {
+        host: String,
+        username: String,
+        client_id: String,
+        redirect_uri: String,
+        secrets: Option<OAuth2SecretsRaw>,
+    },
+}
+
+// CredentialStoreにはjsonにして一つのpasswordとして保存する
+#[derive(Debug, Serialize, Deserialize)]
+struct OAuth2SecretsRaw {
+    client_secret: String,
+    token: OAuth2Token,
+}
+
+impl LoginStatusRaw {
+    fn from(
+        hub: &ClientHub,
+        profile_name: &str,
+        login_status: &LoginStatus,
+        store: &dyn CredentialStore,
+    ) -> Result<Self> {
+        let raw = match login_status {
+            LoginStatus::NotYet => LoginStatusRaw::NotYet,
+            LoginStatus::Polling { token, end_point } => LoginStatusRaw::Polling {
+                token: token.clone(),
+                end_point: end_point.clone(),
+            },
+            LoginStatus::LoggedIn {
+                host,
+                username,
+                password,
+            } => {
+                // 読み込んだときに無かったものは空のまま。保存先には書かない
+                let password = if store.is_plaintext() {
+                    Some(password.clone()).filter(|p| !p.is_empty())
+                } else {
+                    if !password.is_empty() {
+                        store.set(profile_name, username, password)?;
+                    }
+                    None
+                };
+                LoginStatusRaw::LoggedIn {
+                    host: host.clone(),
+                    username: username.clone(),
+                    password,
+                }
+            }
+            LoginStatus::OAuth2 {
+                host,
+                username,
+                client_id,
+                client_secret,
+                redirect_uri,
+                token,
+            } => {
+                let secrets = OAuth2SecretsRaw {
+                    client_secret: client_secret.clone(),
+                    token: hub
+                        .get_oauth2_token(profile_name)
+                        .unwrap_or_else(|| token.clone()),
+                };
+                let lost =
+                    secrets.client_secret.is_empty() && secrets.token.refresh_token.is_empty();
+                let secrets = if lost {
+                    None
+                } else if store.is_plaintext() {
+                    Some(secrets)
+                } else {
+                    let json = serde_json::to_string(&secrets)?;
+                    store.set(profile_name, username, &json)?;
+                    None
+                };
+                LoginStatusRaw::OAuth2 {
+                    host: host.clone(),
+                    username: username.clone(),
+                    client_id: client_id.clone(),
+                    redirect_uri: redirect_uri.clone(),
+                    secrets,
+                }
+            }
+        };
+        Ok(raw)
+    }
+
+    // 保存先に認証情報が無ければ host などは残したまま空にし、再ログインが必要な印を付ける。
+    // NotYet にすると次の保存で host と username が消えてしまう
+    fn to(self, hub: &ClientHub, profile_name: &str) -> Result<LoginStatus> {
+        let store = hub.get_credential_store();
+        let missing = || -> Result<()> {
+            log::warn!(
+                "Credential for {} is not found. Please log in again.",
+                profile_name
+            );
+            hub.mark_relogin_required(profile_name)
+        };
+        let status = match self {
+            LoginStatusRaw::NotYet => LoginStatus::NotYet,
+            LoginStatusRaw::Polling { token, end_point } => {
+                LoginStatus::Polling { token, end_point }
+            }
+            LoginStatusRaw::LoggedIn {
+                host,
+                username,
+                password,
+            } => {
+                // passwordがtomlに残っている場合は次の保存時にstoreへ移る
+                let password = match password {
+                    Some(password) => Some(password),
+                    None => store.get(profile_name, &username)?,
+                };
+                if password.is_none() {
+                    missing()?;
+                }
+                LoginStatus::LoggedIn {
+                    host,
+                    username,
+                    password: password.unwrap_or_default(),
+                }
+            }
+            LoginStatusRaw::OAuth2 {
+                host,
+                username,
+                client_id,
+                redirect_uri,
+                secrets,
+            } => {
+                let secrets = match secrets {
+                    Some(secrets) => Some(secrets),
+                    None => match store.get(profile_name, &username)? {
+                        Some(json) => Some(serde_json::from_str::<OAuth2SecretsRaw>(&json)?),
+                        None => None,
+                    },
+                };
+                let secrets = match secrets {
+                    Some(secrets) => secrets,
+                    None => {
+                        missing()?;
+                        OAuth2SecretsRaw {
+                            client_secret: String::new(),
+                            token: OAuth2Token::new(String::new(), String::new(), 0),
+                        }
+                    }
+                };
+                LoginStatus::OAuth2 {
+                    host,
+                    username,
+                    client_id,
+                    client_secret: secrets.client_secret,
+                    redirect_uri,
+                    token: secrets.token,
+                }
+            }
+        };
+        Ok(status)
+    }
+}
+
+// client identity の password は app password と同じ保存先に置く。username の代わりにこの名前で保存する
+const CLIENT_IDENTITY_ACCOUNT: &str = "#client_identity";
+
+fn network_to_raw(
+    profile_name: &str,
+    network: &NetworkSetting,
+    store: &dyn CredentialStore,
+) -> Result<NetworkSetting> {
+    let mut network = network.clone();
+    if !store.is_plaintext() {
+        if let Some(password) = network.tls.client_identity_password.take() {
+            store.set(profile_name, CLIENT_IDENTITY_ACCOUNT, &password)?;
+        }
+    }
+    Ok(network)
+}
+
+fn network_from_raw(
+    profile_name: &str,
+    mut network: NetworkSetting,
+    store: &dyn CredentialStore,
+) -> Result<NetworkSetting> {
+    // tomlに残っている場合は次の保存時にstoreへ移る
+    if network.tls.client_identity.is_some()
+        && network.tls.client_identity_password.is_none()
+        && !store.is_plaintext()
+    {
+        network.tls.client_identity_password = store.get(profile_name, CLIENT_IDENTITY_ACCOUNT)?;
+    }
+    Ok(network)
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 struct ProfileRaw {
     name: String,
-    login_status: LoginStatus,
+    last_activity_id: Option<u64>,
+    login_status: LoginStatusRaw,
+    #[serde(default)]
+    network: NetworkSetting,
 }
 
 // ClientHubはclient_idを持たなければならないのでLocalInfoRawにDefaultを持たせてはいけない
@@ -16,31 +232,80 @@ struct ProfileRaw {
 struct ClientHubRaw {
     client_id: String,
     default_profile: Option<String>,
+    // 古いprofiles.tomlには無い
+    credential_backend: Option<CredentialBackend>,
     profiles: Vec<ProfileRaw>,
 }
 
 impl ClientHubRaw {
-    fn from(client_hub: &ClientHub) -> ClientHubRaw {
-        ClientHubRaw {
-            client_id: client_hub.client_id.clone(),
-            profiles: client_hub
-                .profiles
-                .iter()
-                .map(|p| ProfileRaw {
+    fn from(client_hub: &ClientHub) -> Result<ClientHubRaw> {
+        let store = client_hub.get_credential_store();
+        let profiles = client_hub
+            .profiles
+            .iter()
+            .map(|p| {
+                Ok(ProfileRaw {
                     name: p.0.clone(),
-                    login_status: p.1.login_status.clone(),
+                    last_activity_id: p.1.last_activity_id,
+                    login_status: LoginStatusRaw::from(client_hub, p.0, &p.1.login_status, store)?,
+                    network: network_to_raw(p.0, &p.1.network, store)?,
                 })
-                .collect(),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(ClientHubRaw {
+            client_id: client_hub.client_id.clone(),
+            credential_backend: Some(client_hub.credential_backend.clone()),
+            profiles,
             default_profile: client_hub.default_profile.clone(),
-        }
+        })
     }
 
-    fn to(self) -> Result<ClientHub> {
-        let mut hub = ClientHub::load_without_profiles(self.client_id)?;
+    // file_path は Keyring が使えないときに暗号化ファイルを置く場所を決めるのに使う。
+    // backend の無い古い profiles.toml に password があれば、読み込んだ後で使える保存先へ移す
+    fn to(self, file_path: &Path) -> Result<ClientHub> {
+        let has_password = self.profiles.iter().any(|p| {
+            matches!(
+                p.login_status,
+                LoginStatusRaw::LoggedIn {
+                    password: Some(_),
+                    ..
+                } | LoginStatusRaw::OAuth2 {
+                    secrets: Some(_),
+                    ..
+                }
+            ) || p.network.tls.client_identity_password.is_some()
+        });
+        let legacy = self.credential_backend.is_none() && has_password;
+        let backend = match self.credential_backend {
+            Some(backend) => backend,
+            None if legacy => CredentialBackend::Plaintext,
+            // 使えなくても、認証情報を保存するまではエラーにしない
+            None => CredentialBackend::detect(Some(file_path)).unwrap_or_default(),
+        };
+        let store = backend.open()?;
+        let mut hub = ClientHub::load_with_credential_store(self.client_id, backend, store)?;
+
         for profile in self.profiles {
-            hub.add_profile(profile.name, profile.login_status)?;
+            let login_status = profile.login_status.to(&hub, &profile.name)?;
+            let network =
+                network_from_raw(&profile.name, profile.network, hub.get_credential_store())?;
+            hub.load_profile(profile.name.clone(), login_status, network)?;
+            if let Some(profile_mut) = hub.get_mut_profile(&profile.name) {
+                profile_mut.last_activity_id = profile.last_activity_id;
+            }
         }
         hub.default_profile = self.default_profile;
+
+        if legacy {
+            let backend = CredentialBackend::detect(Some(file_path)).with_context(|| {
+                format!(
+                    "{} has plaintext passwords. Set [credential_backend] type = \"Plaintext\" to keep them",
+                    file_path.display()
+                )
+            })?;
+            log::warn!("Moving plaintext passwords to {:?}.", backend);
+            migrate_credentials(&mut hub, backend, file_path)?;
+        }
         Ok(hub)
     }
 }
@@ -59,13 +324,16 @@ pub fn client_hub_from_toml(file_path: impl AsRef<Path>) -> Result<ClientHub> {
     let mut hub = match toml_str {
         Ok(s) => {
             let raw: ClientHubRaw = toml::from_str(&s)?;
-            raw.to()?
+            raw.to(file_path)?
         }
         Err(e) => {
             log::info!("{}: {:?}", file_path.display(), e);
-            ClientHub::new()?
+            let backend = CredentialBackend::detect(Some(file_path)).unwrap_or_default();
+            let store = backend.open()?;
+            ClientHub::load_with_credential_store(Uuid::new_v4().to_string(), backend, store)?
         }
     };
+    hub.set_toml_path(file_path);
 
     if_chain! {
         if let Some(ref default_profile) = hub.default_profile;
@@ -80,28 +348,119 @@ pub fn client_hub_from_toml(file_path: impl AsRef<Path>) -> Result<ClientHub> {
 
 pub fn save_client_hub_to_toml(hub: &ClientHub, file_path: impl AsRef<Path>) -> Result<()> {
     let file_path = file_path.as_ref();
-    let hub = ClientHubRaw::from(hub);
+    let hub = ClientHubRaw::from(hub)?;
     let toml_str = toml::to_string(&hub)?;
     fs::write(file_path, toml_str)?;
     Ok(())
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+// 全profileのapp passwordを新しいbackendへ移し、古いbackendからは消す
+pub fn migrate_credentials(
+    hub: &mut ClientHub,
+    backend: CredentialBackend,
+    file_path: impl AsRef<Path>,
+) -> Result<()> {
+    let store = backend.open()?;
+
+    let credentials = hub
+        .profiles
+        .iter()
+        .filter_map(|(name, profile)| match &profile.login_status {
+            LoginStatus::LoggedIn {
+                username, password, ..
+            } => Some((name.clone(), username.clone(), password.clone())),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let oauth2_profiles = hub
+        .profiles
+        .iter()
+        .filter_map(|(name, profile)| {
+            profile
+                .login_status
+                .get_username()
+                .filter(|_| matches!(profile.login_status, LoginStatus::OAuth2 { .. }))
+                .map(|username| (name.clone(), username.to_string()))
+        })
+        .collect::<Vec<_>>();
+    let identity_profiles = hub
+        .profiles
+        .iter()
+        .filter(|(_, profile)| profile.network.tls.client_identity_password.is_some())
+        .map(|(name, _)| (name.clone(), CLIENT_IDENTITY_ACCOUNT.to_string()))
+        .collect::<Vec<_>>();
+
+    for (name, username, password) in credentials.iter() {
+        store.set(name, username, password)?;
+    }
+
+    // OAuth2のtokenとclient identityのpasswordは保存時にstoreへ書き込まれる
+    let old_store = hub.set_credential_store(backend, store);
+    save_client_hub_to_toml(hub, file_path)?;
+
+    let accounts = credentials
+        .into_iter()
+        .map(|(name, username, _)| (name, username))
+        .chain(oauth2_profiles)
+        .chain(identity_profiles);
+    for (name, username) in accounts {
+        if let Err(e) = old_store.delete(&name, &username) {
+            log::warn!("Failed to delete old credential of {}: {:?}", name, e);
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 struct ExcludeListRaw {
     blackpaths: Vec<PathBuf>,
     whitepaths: Vec<PathBuf>,
     blackregexes: Vec<String>,
     whiteregexes: Vec<String>,
+    // 省略すると DEFAULT_RULE_NAMES
+    #[serde(default = "default_rule_names")]
+    default_rules: Vec<String>,
+    // byte
+    max_size: Option<u64>,
+    min_size: Option<u64>,
+    // "2024-01-02" か RFC 3339
+    older_than: Option<String>,
+    newer_than: Option<String>,
+}
+
+impl Default for ExcludeListRaw {
+    fn default() -> Self {
+        Self {
+            blackpaths: Vec::new(),
+            whitepaths: Vec::new(),
+            blackregexes: Vec::new(),
+            whiteregexes: Vec::new(),
+            default_rules: default_rule_names(),
+            max_size: None,
+            min_size: None,
+            older_than: None,
+            newer_than: None,
+        }
+    }
 }
 
 impl ExcludeListRaw {
-    pub fn to(self) -> ExcludeList {
-        ExcludeList::new(
+    pub fn to(self) -> Result<ExcludeList> {
+        Ok(ExcludeList::new(
             self.blackpaths,
             self.whitepaths,
             self.blackregexes,
             self.whiteregexes,
-        )
+        )?
+        .with_default_rules(self.default_rules)?
+        .with_attrs(ExcludeAttrs {
+            max_size: self.max_size,
+            min_size: self.min_size,
+            // lint を通っていれば読める
+            older_than: self.older_than.and_then(|s| parse_exclude_date(&s).ok()),
+            newer_than: self.newer_than.and_then(|s| parse_exclude_date(&s).ok()),
+        }))
     }
 
     pub fn from(exclude_list: &ExcludeList) -> Self {
@@ -110,47 +469,249 @@ impl ExcludeListRaw {
             whitepaths: exclude_list.paths.original_whites.clone(),
             blackregexes: exclude_list.regexes.original_blacks.clone(),
             whiteregexes: exclude_list.regexes.original_whites.clone(),
+            default_rules: exclude_list.regexes.default_rules.clone(),
+            max_size: exclude_list.attrs.max_size,
+            min_size: exclude_list.attrs.min_size,
+            older_than: exclude_list.attrs.older_than.map(|d| d.to_rfc3339()),
+            newer_than: exclude_list.attrs.newer_than.map(|d| d.to_rfc3339()),
         }
     }
 }
 
+// lint 用に ExcludeListRaw と同じキーを読み、各パターンが書かれていた位置も持つ
+#[derive(Debug, Deserialize, Default)]
+struct ExcludeListSpans {
+    #[serde(default)]
+    blackpaths: Vec<Spanned<String>>,
+    #[serde(default)]
+    whitepaths: Vec<Spanned<String>>,
+    #[serde(default)]
+    blackregexes: Vec<Spanned<String>>,
+    #[serde(default)]
+    whiteregexes: Vec<Spanned<String>>,
+    #[serde(default)]
+    default_rules: Vec<Spanned<String>>,
+    older_than: Option<Spanned<String>>,
+    newer_than: Option<Spanned<String>>,
+}
+
+// byte 位置を1始まりの (行, 列) にする
+fn line_column(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset.min(source.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before
+        .rsplit('\n')
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .count()
+        + 1;
+    (line, column)
+}
+
+// Option::as_slice は rust-version より新しい
+fn optional_slice<T>(value: &Option<T>) -> &[T] {
+    value.as_ref().map_or(&[], std::slice::from_ref)
+}
+
+impl ExcludeListSpans {
+    fn lint(&self, location: &str, source: &str) -> Vec<PatternError> {
+        let lists = [
+            (PatternKind::BlackPath, self.blackpaths.as_slice()),
+            (PatternKind::WhitePath, self.whitepaths.as_slice()),
+            (PatternKind::BlackRegex, self.blackregexes.as_slice()),
+            (PatternKind::WhiteRegex, self.whiteregexes.as_slice()),
+            (PatternKind::DefaultRule, self.default_rules.as_slice()),
+            (PatternKind::OlderThan, optional_slice(&self.older_than)),
+            (PatternKind::NewerThan, optional_slice(&self.newer_than)),
+        ];
+        lists
+            .iter()
+            .flat_map(|(kind, patterns)| patterns.iter().map(move |p| (*kind, p)))
+            .filter_map(|(kind, pattern)| {
+                let message = check_pattern(kind, pattern.get_ref()).err()?;
+                Some(PatternError {
+                    location: location.to_string(),
+                    kind,
+                    position: Some(line_column(source, pattern.start())),
+                    pattern: pattern.get_ref().clone(),
+                    message,
+                })
+            })
+            .collect()
+    }
+}
+
+fn lint_exc_list_str(file_path: &Path, toml_str: &str) -> Result<Vec<PatternError>> {
+    let spans: ExcludeListSpans = toml::from_str(toml_str)?;
+    Ok(spans.lint(&file_path.to_string_lossy(), toml_str))
+}
+
+// 不正なパターンを全て返す。読み込みはしない
+pub fn lint_exc_list_toml(file_path: impl AsRef<Path>) -> Result<Vec<PatternError>> {
+    let file_path = file_path.as_ref();
+    let toml_str = fs::read_to_string(file_path)?;
+    toml::from_str::<ExcludeListRaw>(&toml_str)?;
+    lint_exc_list_str(file_path, &toml_str)
+}
+
 pub fn exc_list_from_toml(file_path: impl AsRef<Path>) -> Result<ExcludeList> {
     let file_path = file_path.as_ref();
     let toml_str = fs::read_to_string(file_path)?;
     let exc_list: ExcludeListRaw = toml::from_str(&toml_str)?;
-    let exc_list = exc_list.to();
-    Ok(exc_list)
+    let errors = lint_exc_list_str(file_path, &toml_str)?;
+    if !errors.is_empty() {
+        return Err(InvalidExcludePatterns(errors).into());
+    }
+    exc_list.to()
+}
+
+// tomlの都合上、テーブルになる selective, excludes は最後に置くこと
+#[derive(Debug, Serialize, Deserialize)]
+struct FolderPairRaw {
+    name: String,
+    profile: String,
+    local_root: PathBuf,
+    remote_root: String,
+    #[serde(default)]
+    direction: SyncDirection,
+    // 省略すると daemon の既定の間隔
+    interval_secs: Option<u64>,
+    // true なら明示的に指示されたときだけ同期する
+    #[serde(default)]
+    manual: bool,
+    // true なら新しいファイルは中身を落とさず placeholder を置く
+    #[serde(default)]
+    placeholders: bool,
+    // "skip" か "rename"
+    #[serde(default)]
+    invalid_names: InvalidNamePolicy,
+    #[serde(default)]
+    selective: SelectiveSync,
+    excludes: Option<ExcludeListRaw>,
+}
+
+impl FolderPairRaw {
+    fn to(self) -> Result<FolderPair> {
+        // 0 だと同期し終わった直後にまた同期し続けてしまう
+        if self.interval_secs == Some(0) {
+            return Err(InvalidInterval(self.name).into());
+        }
+        Ok(FolderPair {
+            name: self.name,
+            profile: self.profile,
+            local_root: self.local_root,
+            remote_root: self.remote_root,
+            direction: self.direction,
+            schedule: match (self.manual, self.interval_secs) {
+                (true, _) => Schedule::Manual,
+                (false, Some(secs)) => Schedule::IntervalSecs(secs),
+                (false, None) => Schedule::Default,
+            },
+            placeholders: self.placeholders,
+            invalid_names: self.invalid_names,
+            selective: self.selective,
+            excludes: self.excludes.map(|e| e.to()).transpose()?,
+        })
+    }
+
+    fn from(folder_pair: &FolderPair) -> Self {
+        Self {
+            name: folder_pair.name.clone(),
+            profile: folder_pair.profile.clone(),
+            local_root: folder_pair.local_root.clone(),
+            remote_root: folder_pair.remote_root.clone(),
+            direction: folder_pair.direction,
+            interval_secs: match folder_pair.schedule {
+                Schedule::IntervalSecs(secs) => Some(secs),
+                _ => None,
+            },
+            manual: folder_pair.schedule == Schedule::Manual,
+            placeholders: folder_pair.placeholders,
+            invalid_names: folder_pair.invalid_names,
+            selective: folder_pair.selective.clone(),
+            excludes: folder_pair.excludes.as_ref().map(ExcludeListRaw::from),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
 struct LocalInfoRaw {
     excludes: ExcludeListRaw,
+    // 空だと excludes のテーブルの後に値として書かれてしまう
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    folder_pairs: Vec<FolderPairRaw>,
 }
 
 impl LocalInfoRaw {
-    fn to(self) -> LocalInfo {
-        LocalInfo::new(self.excludes.to())
+    fn to(self) -> Result<LocalInfo> {
+        let mut local_info = LocalInfo::new(self.excludes.to()?);
+        for folder_pair in self.folder_pairs {
+            local_info.add_folder_pair(folder_pair.to()?)?;
+        }
+        Ok(local_info)
     }
 
     fn from(local_info: &LocalInfo) -> Self {
         Self {
             excludes: ExcludeListRaw::from(&local_info.excludes),
+            folder_pairs: local_info
+                .folder_pairs
+                .iter()
+                .map(FolderPairRaw::from)
+                .collect(),
         }
     }
 }
 
-// reqwest clientについては仮置き
-pub fn localinfo_from_toml(file_path: impl AsRef<Path>) -> Result<LocalInfo> {
-    let file_path = file_path.as_ref();
-    let toml_str = fs::read_to_string(file_path);
-    let local_info: LocalInfoRaw = match toml_str {
-        Ok(s) => toml::from_str(&s)?,
+#[derive(Debug, Deserialize, Default)]
+struct FolderPairSpans {
+    excludes: Option<ExcludeListSpans>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct LocalInfoSpans {
+    #[serde(default)]
+    excludes: ExcludeListSpans,
+    #[serde(default)]
+    folder_pairs: Vec<FolderPairSpans>,
+}
+
+// 読み込んだものと、不正なパターン
+fn read_localinfo_raw(file_path: &Path) -> Result<(LocalInfoRaw, Vec<PatternError>)> {
+    let toml_str = match fs::read_to_string(file_path) {
+        Ok(s) => s,
         Err(e) => {
             log::info!("{}: {:?}", file_path.display(), e);
-            LocalInfoRaw::default()
+            return Ok((LocalInfoRaw::default(), Vec::new()));
         }
     };
-    let local_info = local_info.to();
+    let local_info = toml::from_str(&toml_str)?;
+
+    let spans: LocalInfoSpans = toml::from_str(&toml_str)?;
+    let location = file_path.to_string_lossy();
+    let errors = spans
+        .folder_pairs
+        .iter()
+        .filter_map(|f| f.excludes.as_ref())
+        .chain(Some(&spans.excludes))
+        .flat_map(|e| e.lint(&location, &toml_str))
+        .collect();
+    Ok((local_info, errors))
+}
+
+// 不正なパターンを全て返す。読み込みはしない
+pub fn lint_localinfo_toml(file_path: impl AsRef<Path>) -> Result<Vec<PatternError>> {
+    Ok(read_localinfo_raw(file_path.as_ref())?.1)
+}
+
+// reqwest clientについては仮置き
+pub fn localinfo_from_toml(file_path: impl AsRef<Path>) -> Result<LocalInfo> {
+    let (local_info, errors) = read_localinfo_raw(file_path.as_ref())?;
+    if !errors.is_empty() {
+        return Err(InvalidExcludePatterns(errors).into());
+    }
+    let local_info = local_info.to()?;
     Ok(local_info)
 }
 
@@ -209,3 +770,171 @@ pub fn save_curdir_setting_to_toml(
     fs::write(file_path, toml_str)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lint_position_test() {
+        let path = std::env::temp_dir().join(format!("ncsync_lint_{}.toml", uuid::Uuid::new_v4()));
+        let toml_str = r#"[excludes]
+blackpaths = ["*.o", "a[b"]
+whitepaths = []
+blackregexes = []
+whiteregexes = [
+    "(",
+]
+default_rules = ["hidden", "nope"]
+older_than = "yesterday"
+"#;
+        fs::write(&path, toml_str).unwrap();
+
+        let errors = lint_localinfo_toml(&path).unwrap();
+        let found = errors
+            .iter()
+            .map(|e| (e.kind, e.pattern.as_str(), e.position))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            found,
+            vec![
+                (PatternKind::BlackPath, "a[b", Some((2, 22))),
+                (PatternKind::WhiteRegex, "(", Some((6, 5))),
+                (PatternKind::DefaultRule, "nope", Some((8, 28))),
+                (PatternKind::OlderThan, "yesterday", Some((9, 14))),
+            ]
+        );
+        assert!(errors[0]
+            .to_string()
+            .starts_with(&format!("{}:2:22: blackpaths \"a[b\"", path.display())));
+        assert!(localinfo_from_toml(&path).is_err());
+
+        // コードから作るときも黙って捨てない
+        assert!(ExcludeList::new(vec![PathBuf::from("a[b")], vec![], vec![], vec![]).is_err());
+        assert!(ExcludeList::default()
+            .with_default_rules(vec!["nope".to_string()])
+            .is_err());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn interval_test() {
+        let pair = |interval: &str| {
+            let toml_str = format!(
+                "name = \"docs\"\nprofile = \"p\"\nlocal_root = \"/tmp/docs\"\nremote_root = \"/docs\"\n{}",
+                interval
+            );
+            toml::from_str::<FolderPairRaw>(&toml_str).unwrap().to()
+        };
+
+        assert_eq!(pair("").unwrap().schedule, Schedule::Default);
+        assert_eq!(
+            pair("interval_secs = 60").unwrap().schedule,
+            Schedule::IntervalSecs(60)
+        );
+        assert!(pair("interval_secs = 0").is_err());
+        assert_eq!(
+            pair("interval_secs = 0\nmanual = true")
+                .unwrap_err()
+                .to_string(),
+            InvalidInterval("docs".to_string()).to_string()
+        );
+    }
+
+    #[test]
+    fn missing_credential_test() {
+        let path =
+            std::env::temp_dir().join(format!("ncsync_profiles_{}.toml", uuid::Uuid::new_v4()));
+        let toml_str = r#"client_id = "test"
+
+[credential_backend]
+type = "Plaintext"
+
+[[profiles]]
+name = "p"
+
+[profiles.login_status]
+type = "LoggedIn"
+
+[profiles.login_status.info]
+host = "https://cloud.example.com"
+username = "user"
+"#;
+        fs::write(&path, toml_str).unwrap();
+
+        // 認証情報が無くても host と username は残し、再ログインを求める
+        let hub = client_hub_from_toml(&path).unwrap();
+        assert!(hub.is_relogin_required("p"));
+        match &hub.get_profile("p").unwrap().login_status {
+            LoginStatus::LoggedIn { host, username, .. } => {
+                assert_eq!(host, "https://cloud.example.com");
+                assert_eq!(username, "user");
+            }
+            s => panic!("unexpected {:?}", s),
+        }
+
+        save_client_hub_to_toml(&hub, &path).unwrap();
+        let saved = fs::read_to_string(&path).unwrap();
+        assert!(saved.contains("https://cloud.example.com"));
+        assert!(!saved.contains("password"));
+        assert!(client_hub_from_toml(&path)
+            .unwrap()
+            .is_relogin_required("p"));
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn legacy_password_migration_test() {
+        let dir = std::env::temp_dir().join(format!("ncsync_legacy_{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("profiles.toml");
+        let toml_str = r#"client_id = "test"
+
+[[profiles]]
+name = "legacy"
+
+[profiles.login_status]
+type = "LoggedIn"
+
+[profiles.login_status.info]
+host = "https://cloud.example.com"
+username = "user"
+password = "app-password"
+
+[profiles.network.tls]
+client_identity = "/nonexistent/identity.p12"
+client_identity_password = "identity-password"
+"#;
+        fs::write(&path, toml_str).unwrap();
+        std::env::set_var(crate::setting::credential::PASSPHRASE_ENV, "passphrase");
+
+        // 読み込んだときに平文の password を使える保存先へ移す
+        let hub = client_hub_from_toml(&path).unwrap();
+        assert!(!matches!(
+            hub.get_credential_backend(),
+            CredentialBackend::Plaintext
+        ));
+        let saved = fs::read_to_string(&path).unwrap();
+        assert!(!saved.contains("app-password"));
+        assert!(!saved.contains("identity-password"));
+
+        let hub = client_hub_from_toml(&path).unwrap();
+        match &hub.get_profile("legacy").unwrap().login_status {
+            LoginStatus::LoggedIn { password, .. } => assert_eq!(password, "app-password"),
+            s => panic!("unexpected {:?}", s),
+        }
+        assert_eq!(
+            hub.get_profile("legacy")
+                .unwrap()
+                .network
+                .tls
+                .client_identity_password
+                .as_deref(),
+            Some("identity-password")
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,1254 @@
+use crate::communicate::capabilities::capabilities;
+use crate::communicate::changes::{remote_changes_since, RemoteChanges};
+use crate::communicate::delete::delete;
+use crate::communicate::download::download;
+use crate::communicate::ls;
+use crate::communicate::quota::quota;
+use crate::communicate::upload::{mkdir, upload};
+use crate::entry::{Entry, EntryType};
+use crate::errors::NcsError::*;
+use crate::path::{collision_key, normalize_path};
+use crate::setting::{ClientHub, ExcludeList, FolderPair, InvalidNamePolicy};
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+mod naming;
+mod placeholder;
+pub use naming::{NameProblem, NamingRules};
+pub use placeholder::{dehydrate, hydrate, placeholder_path, Placeholder, PLACEHOLDER_SUFFIX};
+
+// ダウンロード中のファイルはこの名前で書いてから置き換える
+pub(crate) const PARTIAL_SUFFIX: &str = ".ncsync.part";
+
+// 除外されておらず、selective sync で選ばれているもの
+fn is_wanted(pair: &FolderPair, exclude_list: &ExcludeList, sync_path: &Path) -> bool {
+    exclude_list.judge(sync_path) && pair.selective.is_synced(sync_path)
+}
+
+fn is_wanted_dir(pair: &FolderPair, exclude_list: &ExcludeList, sync_path: &Path) -> bool {
+    exclude_list.judge_dir(sync_path) && pair.selective.is_synced(sync_path)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct LocalStamp {
+    modified: SystemTime,
+    size: u64,
+}
+
+impl LocalStamp {
+    fn of(path: &Path) -> Option<Self> {
+        let metadata = fs::metadata(path).ok()?;
+        Some(Self {
+            modified: metadata.modified().ok()?,
+            size: metadata.len(),
+        })
+    }
+}
+
+// 前回の同期が終わった時点の状態。folder pair ごとに持ち、ファイルの sync_path ごとに記録する
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SyncState {
+    cursor: Option<u64>,
+    etags: HashMap<PathBuf, String>,
+    local: HashMap<PathBuf, LocalStamp>,
+    // 中身の代わりに placeholder を置いているもの
+    #[serde(default)]
+    placeholders: HashSet<PathBuf>,
+    // リモートでの実際の名前が NFC と違うもの
+    #[serde(default)]
+    remote_names: HashMap<PathBuf, PathBuf>,
+    // 最後に同期したアカウント (username@host)。変わっていたらリモートについて覚えていることを捨てる
+    #[serde(default)]
+    account: Option<String>,
+}
+
+// folder pair ごとの同期状態は state_dir に <name>.json として置く。
+// 名前はそのままファイル名にするので、state_dir の外を指すものは受け付けない
+pub fn state_path(state_dir: impl AsRef<Path>, pair: &FolderPair) -> Result<PathBuf> {
+    let name = &pair.name;
+    if name.is_empty() || name == "." || name == ".." || name.contains(&['/', '\\', '\0'][..]) {
+        return Err(InvalidFolderPairName(name.clone()).into());
+    }
+    Ok(state_dir.as_ref().join(format!("{}.json", name)))
+}
+
+impl SyncState {
+    // まだ無ければ初回の同期として空の状態を返す
+    pub fn load(file_path: impl AsRef<Path>) -> Result<Self> {
+        match fs::read_to_string(file_path.as_ref()) {
+            Ok(s) => Ok(serde_json::from_str(&s)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save(&self, file_path: impl AsRef<Path>) -> Result<()> {
+        write_atomic(file_path.as_ref(), serde_json::to_string(self)?.as_bytes())
+    }
+
+    // ログアウトしたときなど、リモートについて覚えていることを捨てる。手元のファイルは次の同期で比べ直す
+    pub fn forget_remote(&mut self) {
+        self.cursor = None;
+        self.etags.clear();
+        self.placeholders.clear();
+        self.remote_names.clear();
+    }
+
+    // ログアウトして別のアカウントでログインし直したときに、前の etag などを使わないようにする
+    // ログインしていなければ何もしない
+    fn check_account(&mut self, account: Option<String>) {
+        let account = match account {
+            Some(account) => account,
+            None => return,
+        };
+        if matches!(&self.account, Some(a) if *a != account) {
+            log::info!("account changed. forgetting remote state.");
+            self.forget_remote();
+        }
+        self.account = Some(account);
+    }
+
+    // 正規化した sync_path から、リモートで実際に使われている sync_path を求める。
+    // 親ディレクトリの名前が違うときもあるので、中のファイルの名前から辿る
+    fn remote_sync_path(&self, path: &Path) -> PathBuf {
+        if let Some(actual) = self.remote_names.get(path) {
+            return actual.clone();
+        }
+        for ancestor in path.ancestors() {
+            let depth = ancestor.components().count();
+            let actual = self
+                .remote_names
+                .iter()
+                .find(|(k, _)| k.starts_with(ancestor))
+                .map(|(_, actual)| actual.components().take(depth).collect::<PathBuf>());
+            match (actual, path.strip_prefix(ancestor)) {
+                (Some(actual), Ok(rest)) if rest.as_os_str().is_empty() => return actual,
+                (Some(actual), Ok(rest)) => return actual.join(rest),
+                _ => (),
+            }
+        }
+        path.to_path_buf()
+    }
+
+    fn set_remote_name(&mut self, path: &Path, actual: &Path) {
+        if path == actual {
+            self.remote_names.remove(path);
+        } else {
+            self.remote_names
+                .insert(path.to_path_buf(), actual.to_path_buf());
+        }
+    }
+}
+
+// 正規化した sync_path から手元のファイルを探す。無ければ正規化した名前のパス
+fn resolve_local(pair: &FolderPair, path: &Path) -> PathBuf {
+    let exact = pair.local_path(path);
+    if exact.exists() {
+        return exact;
+    }
+
+    let mut res = pair.local_root.clone();
+    for name in path.iter().skip(1) {
+        let candidate = res.join(name);
+        if candidate.exists() {
+            res = candidate;
+            continue;
+        }
+        let found = fs::read_dir(&res).ok().and_then(|entries| {
+            entries
+                .flatten()
+                .map(|e| e.path())
+                .find(|p| p.file_name().map(|n| normalize_path(Path::new(n))) == Some(name.into()))
+        });
+        res = found.unwrap_or(candidate);
+    }
+    res
+}
+
+// 正規化すると同じになるものや、大文字小文字だけが違うもの
+fn find_collisions<'a>(paths: impl Iterator<Item = &'a PathBuf>) -> Vec<Vec<PathBuf>> {
+    let mut groups = HashMap::<String, Vec<PathBuf>>::new();
+    for path in paths {
+        groups
+            .entry(collision_key(path))
+            .or_default()
+            .push(path.clone());
+    }
+    let mut res = groups
+        .into_values()
+        .filter(|g| g.len() > 1)
+        .map(|mut g| {
+            g.sort();
+            g
+        })
+        .collect::<Vec<_>>();
+    res.sort();
+    res
+}
+
+// サーバーが受け付けない名前だったもの
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NameIssue {
+    pub path: PathBuf,
+    pub problem: String,
+    // InvalidNamePolicy::Rename で付け直した名前。None なら上げていない
+    pub renamed_to: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SyncReport {
+    pub downloaded: Vec<PathBuf>,
+    pub uploaded: Vec<PathBuf>,
+    pub deleted_local: Vec<PathBuf>,
+    pub deleted_remote: Vec<PathBuf>,
+    // 両方で変更されたもの。手元の版は conflicted copy として残して上げ直す
+    pub conflicts: Vec<PathBuf>,
+    // 中身を落とさずに placeholder を置いたもの
+    #[serde(default)]
+    pub placeholders: Vec<PathBuf>,
+    #[serde(default)]
+    pub invalid_names: Vec<NameIssue>,
+    // 大文字小文字を区別しないクライアントなどで同じ名前になってしまうもの
+    #[serde(default)]
+    pub collisions: Vec<Vec<PathBuf>>,
+    // 空き容量に入りきらないので送らなかったもの。次の同期でまた試す
+    #[serde(default)]
+    pub over_quota: Vec<PathBuf>,
+    pub errors: Vec<String>,
+}
+
+impl SyncReport {
+    pub fn is_empty(&self) -> bool {
+        self.downloaded.is_empty()
+            && self.uploaded.is_empty()
+            && self.deleted_local.is_empty()
+            && self.deleted_remote.is_empty()
+            && self.conflicts.is_empty()
+            && self.placeholders.is_empty()
+            && self.invalid_names.is_empty()
+            && self.collisions.is_empty()
+            && self.over_quota.is_empty()
+            && self.errors.is_empty()
+    }
+
+    // 認証切れは続けても無駄なので止める
+    fn record(&mut self, path: &Path, e: anyhow::Error) -> Result<()> {
+        if let Some(NotAuthorized) = e.downcast_ref() {
+            return Err(e);
+        }
+        self.errors.push(format!("{}: {:?}", path.display(), e));
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct RemoteFile {
+    // 正規化したもの
+    path: PathBuf,
+    // リモートでの実際の sync_path
+    actual: PathBuf,
+    etag: String,
+    size: usize,
+    last_modified: DateTime<Local>,
+}
+
+#[derive(Debug, Default)]
+struct RemoteSide {
+    dirs: Vec<PathBuf>,
+    files: Vec<RemoteFile>,
+    deleted: Vec<PathBuf>,
+}
+
+fn walk_remote(pair: &FolderPair, entry: &Entry, side: &mut RemoteSide) {
+    let actual = match pair.sync_path_from_remote(&entry.path) {
+        Some(sync_path) => sync_path,
+        None => return,
+    };
+    let sync_path = normalize_path(&actual);
+    match &entry.entry_type {
+        EntryType::File { etag } => {
+            let etag = etag
+                .as_ref()
+                .map(|e| e.get().to_string())
+                .unwrap_or_default();
+            side.files.push(RemoteFile {
+                path: sync_path,
+                actual,
+                etag,
+                size: entry.size,
+                last_modified: entry.last_modified,
+            });
+        }
+        EntryType::Dir { children } => {
+            side.dirs.push(sync_path);
+            for child in children.values() {
+                walk_remote(pair, child, side);
+            }
+        }
+    }
+}
+
+// dir 以下で前回同期していたファイル
+fn known_under<'a>(state: &'a SyncState, dir: &'a Path) -> impl Iterator<Item = &'a PathBuf> {
+    state.etags.keys().filter(move |p| p.starts_with(dir))
+}
+
+fn remote_side(pair: &FolderPair, state: &SyncState, changes: RemoteChanges) -> RemoteSide {
+    let mut side = RemoteSide::default();
+
+    match changes {
+        RemoteChanges::Full { root, .. } => {
+            walk_remote(pair, &root, &mut side);
+            let alive = side.files.iter().map(|f| &f.path).collect::<HashSet<_>>();
+            side.deleted = state
+                .etags
+                .keys()
+                .filter(|p| !alive.contains(p))
+                .cloned()
+                .collect();
+        }
+        RemoteChanges::Incremental { changed, deleted } => {
+            for entry in changed.iter() {
+                walk_remote(pair, entry, &mut side);
+            }
+            // 取り直したディレクトリに無くなっているものは移動か削除された
+            let alive = side.files.iter().map(|f| &f.path).collect::<HashSet<_>>();
+            let mut gone = HashSet::new();
+            for dir in side.dirs.iter() {
+                gone.extend(
+                    known_under(state, dir)
+                        .filter(|p| !alive.contains(p))
+                        .cloned(),
+                );
+            }
+            for path in deleted
+                .iter()
+                .filter_map(|p| pair.sync_path_from_remote(p))
+                .map(|p| normalize_path(&p))
+            {
+                gone.extend(known_under(state, &path).cloned());
+            }
+            side.deleted = gone.into_iter().collect();
+        }
+    }
+    side.deleted.sort();
+
+    side
+}
+
+fn write_atomic(path: &Path, bytes: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(PARTIAL_SUFFIX);
+    let partial = PathBuf::from(partial);
+
+    fs::write(&partial, bytes)?;
+    fs::rename(&partial, path)?;
+
+    Ok(())
+}
+
+// ファイルを消して中身が無くなったディレクトリを local_root の手前まで消す
+fn remove_empty_dirs(pair: &FolderPair, mut dirs: Vec<PathBuf>) {
+    dirs.sort();
+    dirs.dedup();
+    for dir in dirs.iter().rev() {
+        for dir in dir.ancestors() {
+            if dir == pair.local_root || !dir.starts_with(&pair.local_root) {
+                break;
+            }
+            if fs::remove_dir(dir).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+enum Pulled {
+    Written,
+    // 手元に同じ内容があった
+    Same,
+    // 手元の版は conflicted copy に退避した
+    Conflicted,
+}
+
+// "a.txt" -> "a (conflicted copy 2024-01-02 030405).txt"
+fn conflicted_copy_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let now = Local::now().format("%Y-%m-%d %H%M%S");
+    let name = match path.extension() {
+        Some(ext) => format!(
+            "{} (conflicted copy {}).{}",
+            stem,
+            now,
+            ext.to_string_lossy()
+        ),
+        None => format!("{} (conflicted copy {})", stem, now),
+    };
+    path.with_file_name(name)
+}
+
+// リモートの変更を取り込み、触ったパスを返す
+async fn pull(
+    client_hub: &ClientHub,
+    pair: &FolderPair,
+    exclude_list: &ExcludeList,
+    state: &mut SyncState,
+    report: &mut SyncReport,
+) -> Result<HashSet<PathBuf>> {
+    let (changes, cursor) = remote_changes_since(
+        &pair.profile,
+        client_hub,
+        &pair.remote_root,
+        None,
+        state.cursor,
+    )
+    .await?;
+    let side = remote_side(pair, state, changes);
+    let mut touched = HashSet::new();
+    let errors = report.errors.len();
+
+    // どれを落としても同じ手元のファイルを上書きし合うので、全て飛ばす。
+    // touched に入れて push でも触らない
+    let collisions = find_collisions(side.files.iter().map(|f| &f.actual));
+    let colliding = collisions
+        .iter()
+        .flatten()
+        .map(|p| normalize_path(p))
+        .collect::<HashSet<_>>();
+    touched.extend(colliding.iter().cloned());
+    report.collisions.extend(collisions);
+
+    for dir in side
+        .dirs
+        .iter()
+        .filter(|p| is_wanted_dir(pair, exclude_list, p))
+    {
+        if let Err(e) = fs::create_dir_all(resolve_local(pair, dir)) {
+            report.record(dir, e.into())?;
+        }
+    }
+
+    for file in side.files.iter() {
+        let (path, etag) = (&file.path, &file.etag);
+        if colliding.contains(path)
+            || !is_wanted(pair, exclude_list, path)
+            || !exclude_list.judge_file(path, file.size as u64, file.last_modified)
+            || state.etags.get(path) == Some(etag)
+        {
+            continue;
+        }
+
+        let local = resolve_local(pair, path);
+        let stamp = LocalStamp::of(&local);
+
+        // 手元に無く hydrate もされていないものは placeholder だけ置く
+        if pair.placeholders && stamp.is_none() && !state.local.contains_key(path) {
+            let placeholder = Placeholder {
+                remote_path: pair.remote_path(&file.actual),
+                etag: etag.clone(),
+                size: file.size as u64,
+                last_modified: file.last_modified.to_rfc3339(),
+            };
+            match placeholder.write(placeholder_path(&local)) {
+                Ok(()) => {
+                    state.etags.insert(path.clone(), etag.clone());
+                    state.set_remote_name(path, &file.actual);
+                    state.placeholders.insert(path.clone());
+                    report.placeholders.push(path.clone());
+                    touched.insert(path.clone());
+                }
+                Err(e) => report.record(path, e)?,
+            }
+            continue;
+        }
+        let changed_locally = stamp.is_some() && stamp != state.local.get(path).copied();
+
+        let res = async {
+            let bytes = download(&pair.profile, client_hub, pair.remote_path(&file.actual)).await?;
+            if !changed_locally {
+                write_atomic(&local, &bytes)?;
+                return Ok(Pulled::Written);
+            }
+            if fs::read(&local)? == bytes.as_ref() {
+                return Ok(Pulled::Same);
+            }
+            fs::rename(&local, conflicted_copy_path(&local))?;
+            write_atomic(&local, &bytes)?;
+            Ok(Pulled::Conflicted)
+        }
+        .await;
+
+        match res {
+            Ok(pulled) => {
+                state.etags.insert(path.clone(), etag.clone());
+                state.set_remote_name(path, &file.actual);
+                if let Some(stamp) = LocalStamp::of(&local) {
+                    state.local.insert(path.clone(), stamp);
+                }
+                match pulled {
+                    Pulled::Written => report.downloaded.push(path.clone()),
+                    Pulled::Conflicted => report.conflicts.push(path.clone()),
+                    Pulled::Same => (),
+                }
+                touched.insert(path.clone());
+            }
+            Err(e) => report.record(path, e)?,
+        }
+    }
+
+    let mut deleted_dirs = Vec::new();
+    for path in side.deleted.iter() {
+        state.etags.remove(path);
+        state.remote_names.remove(path);
+        let known = state.local.remove(path);
+        touched.insert(path.clone());
+        if !is_wanted(pair, exclude_list, path) {
+            continue;
+        }
+
+        let local = resolve_local(pair, path);
+        if state.placeholders.remove(path) {
+            match fs::remove_file(placeholder_path(&local)) {
+                Ok(()) => report.deleted_local.push(path.clone()),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                Err(e) => report.record(path, e.into())?,
+            }
+        }
+        // 手元で変更されていれば消さずに新しいファイルとして上げ直す
+        match LocalStamp::of(&local) {
+            Some(stamp) if Some(stamp) == known => match fs::remove_file(&local) {
+                Ok(()) => report.deleted_local.push(path.clone()),
+                Err(e) => report.record(path, e.into())?,
+            },
+            Some(_) => {
+                touched.remove(path);
+            }
+            None => (),
+        }
+        if let Some(parent) = local.parent() {
+            deleted_dirs.push(parent.to_path_buf());
+        }
+    }
+    remove_empty_dirs(pair, deleted_dirs);
+
+    // 取りこぼしがあれば次回も同じ所から取り直す
+    if report.errors.len() == errors {
+        state.cursor = cursor;
+    }
+
+    Ok(touched)
+}
+
+// res のキーは正規化した sync_path。names には手元での実際の sync_path を積む
+fn scan_local(
+    pair: &FolderPair,
+    exclude_list: &ExcludeList,
+    state: &SyncState,
+    dir: &Path,
+    res: &mut HashMap<PathBuf, LocalStamp>,
+    names: &mut Vec<PathBuf>,
+) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let local = entry.path();
+        let file_type = entry.file_type()?;
+        if file_type.is_symlink() || local.to_string_lossy().ends_with(PARTIAL_SUFFIX) {
+            continue;
+        }
+
+        let sync_path = match local.strip_prefix(&pair.local_root) {
+            Ok(relative) => Path::new("/").join(relative),
+            Err(_) => continue,
+        };
+        let wanted = if file_type.is_dir() {
+            is_wanted_dir(pair, exclude_list, &sync_path)
+        } else {
+            is_wanted(pair, exclude_list, &sync_path)
+        };
+        if !wanted
+            || (file_type.is_file() && placeholder::is_placeholder(state, &sync_path, &local))
+        {
+            continue;
+        }
+
+        if file_type.is_dir() {
+            scan_local(pair, exclude_list, state, &local, res, names)?;
+        } else if let Some(stamp) = LocalStamp::of(&local) {
+            if exclude_list.judge_file(&sync_path, stamp.size, stamp.modified.into()) {
+                res.insert(normalize_path(&sync_path), stamp);
+                names.push(sync_path);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// capabilities が取れなければ既定の規則で調べる
+async fn naming_rules(client_hub: &ClientHub, pair: &FolderPair) -> Result<NamingRules> {
+    match capabilities(&pair.profile, client_hub).await {
+        Ok(capabilities) => Ok(NamingRules::from_capabilities(&capabilities.files)),
+        Err(e) => {
+            if let Some(NotAuthorized) = e.downcast_ref() {
+                return Err(e);
+            }
+            log::warn!("could not get capabilities of {}: {:?}", pair.profile, e);
+            Ok(NamingRules::default())
+        }
+    }
+}
+
+// 最初に引っかかった要素までのパスと、その理由
+fn find_invalid_names(
+    rules: &NamingRules,
+    scanned: &HashMap<PathBuf, LocalStamp>,
+) -> BTreeMap<PathBuf, NameProblem> {
+    let mut res = BTreeMap::new();
+    for path in scanned.keys() {
+        let mut prefix = PathBuf::from("/");
+        for name in path.iter().skip(1) {
+            prefix.push(name);
+            if let Some(problem) = rules.check(&name.to_string_lossy()) {
+                res.insert(prefix, problem);
+                break;
+            }
+        }
+    }
+    res
+}
+
+// 付け直した sync_path を返す。同じ名前のものが既にあれば付け直さない
+fn rename_invalid(pair: &FolderPair, rules: &NamingRules, path: &Path) -> Option<PathBuf> {
+    let fixed = rules.fix(&path.file_name()?.to_string_lossy())?;
+    let renamed = path.with_file_name(fixed);
+    let from = resolve_local(pair, path);
+    let to = from.with_file_name(renamed.file_name()?);
+    if to.exists() {
+        return None;
+    }
+    match fs::rename(&from, &to) {
+        Ok(()) => Some(renamed),
+        Err(e) => {
+            log::warn!("could not rename {}: {:?}", from.display(), e);
+            None
+        }
+    }
+}
+
+// 前回から変わっていて、pull で触っていないもの
+// 空き容量に入る分だけ送る。小さいものから詰めて、なるべく多く送れるようにする
+fn split_by_quota(
+    paths: Vec<PathBuf>,
+    scanned: &HashMap<PathBuf, LocalStamp>,
+    available: Option<u64>,
+) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    let available = match available {
+        Some(available) => available,
+        None => return (paths, Vec::new()),
+    };
+    let mut by_size = paths.iter().collect::<Vec<_>>();
+    by_size.sort_by_key(|p| scanned[*p].size);
+    let mut total = 0u64;
+    let fits = by_size
+        .into_iter()
+        .take_while(|p| {
+            total = total.saturating_add(scanned[*p].size);
+            total <= available
+        })
+        .cloned()
+        .collect::<HashSet<_>>();
+    paths.into_iter().partition(|p| fits.contains(p))
+}
+
+fn pending_uploads(
+    scanned: &HashMap<PathBuf, LocalStamp>,
+    state: &SyncState,
+    touched: &HashSet<PathBuf>,
+) -> Vec<PathBuf> {
+    let mut paths = scanned
+        .iter()
+        .filter(|(p, stamp)| !touched.contains(*p) && state.local.get(*p) != Some(stamp))
+        .map(|(p, _)| p.clone())
+        .collect::<Vec<_>>();
+    paths.sort();
+    paths
+}
+
+// 前回あったのに無くなったもの
+fn removed_locally(
+    scanned: &HashMap<PathBuf, LocalStamp>,
+    state: &SyncState,
+    touched: &HashSet<PathBuf>,
+) -> Vec<PathBuf> {
+    let mut paths = state
+        .local
+        .keys()
+        .filter(|p| !scanned.contains_key(*p) && !touched.contains(*p))
+        .cloned()
+        .collect::<Vec<_>>();
+    paths.sort();
+    paths
+}
+
+fn has_files(entry: &Entry) -> bool {
+    match &entry.entry_type {
+        EntryType::File { .. } => true,
+        EntryType::Dir { children } => children.values().any(has_files),
+    }
+}
+
+// アップロードの返事に ETag が無ければ取り直す。空のまま記録すると次の pull でまた落としてしまう
+async fn uploaded_etag(client_hub: &ClientHub, pair: &FolderPair, remote: &Path) -> Result<String> {
+    let entry = ls(&pair.profile, client_hub, &remote.to_string_lossy()).await?;
+    match entry.entry_type {
+        EntryType::File { etag: Some(etag) } => Ok(etag.get().to_string()),
+        _ => Err(anyhow!("no etag for {}", remote.display())),
+    }
+}
+
+// 手元の変更を送る。pull で触ったパスは対象にしない
+async fn push(
+    client_hub: &ClientHub,
+    pair: &FolderPair,
+    exclude_list: &ExcludeList,
+    state: &mut SyncState,
+    report: &mut SyncReport,
+    touched: &HashSet<PathBuf>,
+) -> Result<()> {
+    let mut scanned = HashMap::new();
+    let mut names = Vec::new();
+    scan_local(
+        pair,
+        exclude_list,
+        state,
+        &pair.local_root,
+        &mut scanned,
+        &mut names,
+    )?;
+
+    // サーバーに弾かれるものは途中で失敗する前に除く
+    let rules = naming_rules(client_hub, pair).await?;
+    let mut renamed_any = false;
+    for (path, problem) in find_invalid_names(&rules, &scanned) {
+        let renamed_to = match pair.invalid_names {
+            InvalidNamePolicy::Skip => None,
+            InvalidNamePolicy::Rename => rename_invalid(pair, &rules, &path),
+        };
+        renamed_any |= renamed_to.is_some();
+        report.invalid_names.push(NameIssue {
+            path,
+            problem: problem.to_string(),
+            renamed_to,
+        });
+    }
+    if renamed_any {
+        scanned.clear();
+        names.clear();
+        scan_local(
+            pair,
+            exclude_list,
+            state,
+            &pair.local_root,
+            &mut scanned,
+            &mut names,
+        )?;
+    }
+    report.collisions.extend(find_collisions(names.iter()));
+    // 手元に残したものは下の削除でリモートから消されない
+    let invalid = find_invalid_names(&rules, &scanned);
+    scanned.retain(|p, _| !invalid.keys().any(|i| p.starts_with(i)));
+
+    let paths = pending_uploads(&scanned, state, touched);
+    let mut made_dirs = HashSet::new();
+
+    // 途中で507になる前に、入りきらないものは除いておく。送らなかったものは次の同期でまた試す
+    let total_size = paths.iter().map(|p| scanned[p].size).sum::<u64>();
+    let paths = if total_size > 0 {
+        let quota = quota(&pair.profile, client_hub, &pair.remote_root).await?;
+        let (paths, over_quota) = split_by_quota(paths, &scanned, quota.available);
+        report.over_quota.extend(over_quota);
+        paths
+    } else {
+        paths
+    };
+
+    for path in paths.iter() {
+        let stamp = scanned[path];
+
+        let res = async {
+            for dir in path
+                .ancestors()
+                .skip(1)
+                .collect::<Vec<_>>()
+                .into_iter()
+                .rev()
+            {
+                if dir == Path::new("/") || made_dirs.contains(dir) {
+                    continue;
+                }
+                let remote = pair.remote_path(&state.remote_sync_path(dir));
+                mkdir(&pair.profile, client_hub, remote).await?;
+                made_dirs.insert(dir.to_path_buf());
+            }
+            let bytes = fs::read(resolve_local(pair, path))?;
+            let remote = pair.remote_path(&state.remote_sync_path(path));
+            match upload(&pair.profile, client_hub, &remote, bytes).await? {
+                Some(etag) => Ok(etag.get().to_string()),
+                None => uploaded_etag(client_hub, pair, &remote).await,
+            }
+        }
+        .await;
+
+        match res {
+            Ok(etag) => {
+                let actual = state.remote_sync_path(path);
+                state.set_remote_name(path, &actual);
+                state.etags.insert(path.clone(), etag);
+                state.local.insert(path.clone(), stamp);
+                report.uploaded.push(path.clone());
+            }
+            Err(e) => report.record(path, e)?,
+        }
+    }
+
+    // 前回あったのに無くなったものはリモートからも消す
+    let removed = removed_locally(&scanned, state, touched);
+    // 消したファイルを含んでいて手元に無くなったディレクトリ。値はリモートでの実際の sync_path
+    let mut removed_dirs = BTreeMap::new();
+    for path in removed.iter() {
+        state.local.remove(path);
+        // 除外されたり選択から外れたりしただけなら消さない。
+        // 手元にあるのに scan されなかったものは大きさや更新日時で除外された
+        if !is_wanted(pair, exclude_list, path) || resolve_local(pair, path).exists() {
+            continue;
+        }
+        let actual = state.remote_sync_path(path);
+        match delete(&pair.profile, client_hub, pair.remote_path(&actual)).await {
+            Ok(()) => {
+                state.etags.remove(path);
+                state.remote_names.remove(path);
+                report.deleted_remote.push(path.clone());
+                for (dir, actual_dir) in path.ancestors().zip(actual.ancestors()).skip(1) {
+                    if dir == Path::new("/") || resolve_local(pair, dir).exists() {
+                        break;
+                    }
+                    removed_dirs.insert(dir.to_path_buf(), actual_dir.to_path_buf());
+                }
+            }
+            Err(e) => report.record(path, e)?,
+        }
+    }
+
+    // 手元で消されたディレクトリは、リモートでも中にファイルが残っていなければ消す。
+    // 親から順に見るので、消したものの中は飛ばす
+    let mut deleted_dirs = Vec::<PathBuf>::new();
+    for (dir, actual) in removed_dirs.iter() {
+        if deleted_dirs.iter().any(|d| dir.starts_with(d)) {
+            continue;
+        }
+        let remote = pair.remote_path(actual);
+        let res = async {
+            let entry = ls(&pair.profile, client_hub, &remote.to_string_lossy()).await?;
+            if has_files(&entry) {
+                return Ok(false);
+            }
+            delete(&pair.profile, client_hub, &remote).await?;
+            Ok::<_, anyhow::Error>(true)
+        }
+        .await;
+        match res {
+            Ok(true) => {
+                deleted_dirs.push(dir.clone());
+                report.deleted_remote.push(dir.clone());
+            }
+            Ok(false) => (),
+            Err(e) => match e.downcast_ref() {
+                Some(BadStatusError(404)) => deleted_dirs.push(dir.clone()),
+                _ => report.record(dir, e)?,
+            },
+        }
+    }
+
+    Ok(())
+}
+
+// folder pair を direction に従って一度同期する。ファイル単位の失敗は SyncReport::errors に積んで続ける
+fn account_of(client_hub: &ClientHub, pair: &FolderPair) -> Option<String> {
+    let login_status = &client_hub.get_profile(&pair.profile)?.login_status;
+    Some(format!(
+        "{}@{}",
+        login_status.get_username()?,
+        login_status.get_host_str()?
+    ))
+}
+
+pub async fn sync_once(
+    client_hub: &ClientHub,
+    pair: &FolderPair,
+    exclude_list: &ExcludeList,
+    state: &mut SyncState,
+) -> Result<SyncReport> {
+    // 外付けディスクが外れているときなどに全部消したと思われないようにする
+    if !pair.local_root.is_dir() {
+        return Err(InvalidPathError(pair.local_root.to_string_lossy().to_string()).into());
+    }
+    // .ncsyncignore は毎回読み直す
+    let exclude_list = &exclude_list.load_ignore_files(&pair.local_root)?;
+    state.check_account(account_of(client_hub, pair));
+
+    let mut report = SyncReport::default();
+    let touched = if pair.direction.pulls() {
+        pull(client_hub, pair, exclude_list, state, &mut report).await?
+    } else {
+        HashSet::new()
+    };
+    if pair.direction.pushes() {
+        push(client_hub, pair, exclude_list, state, &mut report, &touched).await?;
+    }
+    // pull と push で同じ組を見つけることがある
+    report.collisions.sort();
+    report.collisions.dedup();
+
+    Ok(report)
+}
+
+// SelectiveSync を変えたあとに呼ぶ。選択から外れたものを手元から消し、新しく選ばれたものを取得する
+pub async fn reselect(
+    client_hub: &ClientHub,
+    pair: &FolderPair,
+    exclude_list: &ExcludeList,
+    state: &mut SyncState,
+) -> Result<SyncReport> {
+    state.check_account(account_of(client_hub, pair));
+    let mut report = SyncReport::default();
+
+    let mut removed_dirs = Vec::new();
+    let unselected_placeholders = state
+        .placeholders
+        .iter()
+        .filter(|p| !pair.selective.is_synced(p))
+        .cloned()
+        .collect::<Vec<_>>();
+    for path in unselected_placeholders.iter() {
+        state.etags.remove(path);
+        state.remote_names.remove(path);
+        state.placeholders.remove(path);
+        let local = resolve_local(pair, path);
+        if fs::remove_file(placeholder_path(&local)).is_ok() {
+            report.deleted_local.push(path.clone());
+        }
+        if let Some(parent) = local.parent() {
+            removed_dirs.push(parent.to_path_buf());
+        }
+    }
+
+    let mut unselected = state
+        .local
+        .keys()
+        .filter(|p| !pair.selective.is_synced(p))
+        .cloned()
+        .collect::<Vec<_>>();
+    unselected.sort();
+
+    for path in unselected.iter() {
+        state.etags.remove(path);
+        state.remote_names.remove(path);
+        let known = state.local.remove(path);
+
+        let local = resolve_local(pair, path);
+        match LocalStamp::of(&local) {
+            Some(stamp) if Some(stamp) == known => match fs::remove_file(&local) {
+                Ok(()) => report.deleted_local.push(path.clone()),
+                Err(e) => report.record(path, e.into())?,
+            },
+            // 手元で変更されたものは消さずに残す
+            Some(_) => report.conflicts.push(path.clone()),
+            None => (),
+        }
+        if let Some(parent) = local.parent() {
+            removed_dirs.push(parent.to_path_buf());
+        }
+    }
+    remove_empty_dirs(pair, removed_dirs);
+
+    // 新しく選ばれたものは activity に現れないので全体を取り直す
+    state.cursor = None;
+    let synced = sync_once(client_hub, pair, exclude_list, state).await?;
+
+    report.downloaded.extend(synced.downloaded);
+    report.uploaded.extend(synced.uploaded);
+    report.deleted_local.extend(synced.deleted_local);
+    report.deleted_remote.extend(synced.deleted_remote);
+    report.conflicts.extend(synced.conflicts);
+    report.placeholders.extend(synced.placeholders);
+    report.invalid_names.extend(synced.invalid_names);
+    report.collisions.extend(synced.collisions);
+    report.over_quota.extend(synced.over_quota);
+    report.errors.extend(synced.errors);
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entry::Etag;
+    use std::time::Duration;
+
+    fn file(path: &str, etag: &str) -> Entry {
+        Entry::new(
+            PathBuf::from(path),
+            EntryType::new_file(Some(Etag::new(etag))),
+            Local::now(),
+            1,
+        )
+    }
+
+    fn dir(path: &str, children: Vec<Entry>) -> Entry {
+        let children = children.into_iter().map(|e| (e.path.clone(), e)).collect();
+        Entry::new(
+            PathBuf::from(path),
+            EntryType::Dir { children },
+            Local::now(),
+            0,
+        )
+    }
+
+    fn stamp(secs: u64) -> LocalStamp {
+        LocalStamp {
+            modified: SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+            size: secs,
+        }
+    }
+
+    fn paths(v: &[&str]) -> Vec<PathBuf> {
+        v.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn remote_side_test() {
+        let pair = FolderPair::new("test", "profile", "/tmp/ncsync_test", "/Sync");
+        let mut state = SyncState::default();
+        for (p, etag) in [("/a.txt", "1"), ("/gone.txt", "2"), ("/d/old.txt", "3")] {
+            state.etags.insert(PathBuf::from(p), etag.to_string());
+        }
+
+        let root = dir(
+            "/Sync",
+            vec![
+                file("/Sync/a.txt", "1"),
+                dir("/Sync/d", vec![file("/Sync/d/b.txt", "4")]),
+            ],
+        );
+        let side = remote_side(
+            &pair,
+            &state,
+            RemoteChanges::Full {
+                root,
+                changed: vec![],
+                deleted: vec![],
+            },
+        );
+        let mut files = side
+            .files
+            .iter()
+            .map(|f| f.path.clone())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files, paths(&["/a.txt", "/d/b.txt"]));
+        assert_eq!(side.deleted, paths(&["/d/old.txt", "/gone.txt"]));
+
+        // 取り直したディレクトリに無いものと、消されたディレクトリの中のもの
+        state
+            .etags
+            .insert(PathBuf::from("/e/x.txt"), "5".to_string());
+        let side = remote_side(
+            &pair,
+            &state,
+            RemoteChanges::Incremental {
+                changed: vec![dir("/Sync/d", vec![file("/Sync/d/b.txt", "4")])],
+                deleted: paths(&["/Sync/e"]),
+            },
+        );
+        assert_eq!(side.dirs, paths(&["/d"]));
+        assert_eq!(side.files.len(), 1);
+        assert_eq!(side.deleted, paths(&["/d/old.txt", "/e/x.txt"]));
+    }
+
+    #[test]
+    fn push_plan_test() {
+        let mut state = SyncState::default();
+        state.local.insert(PathBuf::from("/same.txt"), stamp(1));
+        state.local.insert(PathBuf::from("/changed.txt"), stamp(1));
+        state.local.insert(PathBuf::from("/pulled.txt"), stamp(1));
+        state.local.insert(PathBuf::from("/removed.txt"), stamp(1));
+        state
+            .local
+            .insert(PathBuf::from("/pulled_gone.txt"), stamp(1));
+
+        let scanned = [
+            ("/same.txt", stamp(1)),
+            ("/changed.txt", stamp(2)),
+            ("/pulled.txt", stamp(2)),
+            ("/new.txt", stamp(3)),
+        ]
+        .iter()
+        .map(|(p, s)| (PathBuf::from(p), *s))
+        .collect::<HashMap<_, _>>();
+        let touched = paths(&["/pulled.txt", "/pulled_gone.txt"])
+            .into_iter()
+            .collect::<HashSet<_>>();
+
+        assert_eq!(
+            pending_uploads(&scanned, &state, &touched),
+            paths(&["/changed.txt", "/new.txt"])
+        );
+        assert_eq!(
+            removed_locally(&scanned, &state, &touched),
+            paths(&["/removed.txt"])
+        );
+    }
+
+    #[test]
+    fn split_by_quota_test() {
+        let scanned = [("/a", stamp(5)), ("/b", stamp(3)), ("/c", stamp(4))]
+            .iter()
+            .map(|(p, s)| (PathBuf::from(p), *s))
+            .collect::<HashMap<_, _>>();
+        let all = paths(&["/a", "/b", "/c"]);
+
+        assert_eq!(
+            split_by_quota(all.clone(), &scanned, Some(8)),
+            (paths(&["/b", "/c"]), paths(&["/a"]))
+        );
+        assert_eq!(
+            split_by_quota(all.clone(), &scanned, Some(2)),
+            (vec![], all.clone())
+        );
+        assert_eq!(split_by_quota(all.clone(), &scanned, None), (all, vec![]));
+    }
+
+    #[test]
+    fn sync_state_round_trip_test() {
+        let file_path =
+            std::env::temp_dir().join(format!("ncsync_state_{}.json", uuid::Uuid::new_v4()));
+        assert!(SyncState::load(&file_path).unwrap().etags.is_empty());
+
+        let mut state = SyncState {
+            cursor: Some(42),
+            ..Default::default()
+        };
+        state
+            .etags
+            .insert(PathBuf::from("/a/b.txt"), "etag".to_string());
+        state.local.insert(PathBuf::from("/a/b.txt"), stamp(10));
+        state.placeholders.insert(PathBuf::from("/c.txt"));
+        state.set_remote_name(Path::new("/a/b.txt"), Path::new("/A/b.txt"));
+        state.save(&file_path).unwrap();
+
+        let loaded = SyncState::load(&file_path).unwrap();
+        assert_eq!(loaded.cursor, Some(42));
+        assert_eq!(loaded.etags, state.etags);
+        assert_eq!(loaded.local, state.local);
+        assert_eq!(loaded.placeholders, state.placeholders);
+        assert_eq!(loaded.remote_names, state.remote_names);
+
+        // 古い形式には placeholders と remote_names が無い
+        fs::write(&file_path, r#"{"cursor":null,"etags":{},"local":{}}"#).unwrap();
+        assert!(SyncState::load(&file_path).is_ok());
+
+        fs::remove_file(file_path).unwrap();
+    }
+
+    #[test]
+    fn check_account_test() {
+        let mut state = SyncState {
+            cursor: Some(1),
+            ..Default::default()
+        };
+        state.check_account(Some("a@https://cloud.example.com".to_string()));
+        assert_eq!(state.cursor, Some(1));
+
+        // ログアウト中は変えない
+        state.check_account(None);
+        assert_eq!(state.cursor, Some(1));
+
+        state.check_account(Some("b@https://cloud.example.com".to_string()));
+        assert_eq!(state.cursor, None);
+        assert_eq!(
+            state.account.as_deref(),
+            Some("b@https://cloud.example.com")
+        );
+    }
+
+    #[test]
+    fn remote_sync_path_test() {
+        let mut state = SyncState::default();
+        state.set_remote_name(Path::new("/docs/a.txt"), Path::new("/Docs/a.txt"));
+        state.set_remote_name(Path::new("/same.txt"), Path::new("/same.txt"));
+        assert!(!state.remote_names.contains_key(Path::new("/same.txt")));
+
+        assert_eq!(
+            state.remote_sync_path(Path::new("/docs/a.txt")),
+            PathBuf::from("/Docs/a.txt")
+        );
+        // 同じディレクトリの新しいファイルとディレクトリ自体
+        assert_eq!(
+            state.remote_sync_path(Path::new("/docs/new.txt")),
+            PathBuf::from("/Docs/new.txt")
+        );
+        assert_eq!(
+            state.remote_sync_path(Path::new("/docs")),
+            PathBuf::from("/Docs")
+        );
+        assert_eq!(
+            state.remote_sync_path(Path::new("/other/b.txt")),
+            PathBuf::from("/other/b.txt")
+        );
+    }
+
+    #[test]
+    fn resolve_local_test() {
+        let root = std::env::temp_dir().join(format!("ncsync_resolve_{}", uuid::Uuid::new_v4()));
+        // 手元では NFD
+        fs::create_dir_all(root.join("cafe\u{301}")).unwrap();
+        fs::write(root.join("cafe\u{301}").join("a.txt"), "a").unwrap();
+        let pair = FolderPair::new("test", "profile", &root, "/Sync");
+
+        assert_eq!(
+            resolve_local(&pair, Path::new("/caf\u{e9}/a.txt")),
+            root.join("cafe\u{301}").join("a.txt")
+        );
+        // 無いものは正規化した名前のまま
+        assert_eq!(
+            resolve_local(&pair, Path::new("/caf\u{e9}/new.txt")),
+            root.join("cafe\u{301}").join("new.txt")
+        );
+        assert_eq!(
+            resolve_local(&pair, Path::new("/x/y.txt")),
+            root.join("x").join("y.txt")
+        );
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn find_collisions_test() {
+        let names = paths(&["/A.txt", "/a.txt", "/b.txt", "/cafe\u{301}", "/caf\u{e9}"]);
+        assert_eq!(
+            find_collisions(names.iter()),
+            vec![
+                paths(&["/A.txt", "/a.txt"]),
+                paths(&["/cafe\u{301}", "/caf\u{e9}"]),
+            ]
+        );
+    }
+}
@@ -0,0 +1,190 @@
+use crate::communicate::capabilities::FilesCapabilities;
+use std::fmt::Display;
+
+// capabilities に何も無い古いサーバーでも弾かれるもの
+const DEFAULT_FORBIDDEN_NAMES: &[&str] = &[".htaccess"];
+const DEFAULT_FORBIDDEN_CHARACTERS: &[&str] = &["\\"];
+const DEFAULT_FORBIDDEN_EXTENSIONS: &[&str] = &[" ", ".", ".filepart"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NameProblem {
+    ForbiddenName(String),
+    ForbiddenBasename(String),
+    ForbiddenCharacter(String),
+    ForbiddenExtension(String),
+}
+
+impl Display for NameProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NameProblem::ForbiddenName(s) => write!(f, "forbidden name {:?}", s),
+            NameProblem::ForbiddenBasename(s) => write!(f, "forbidden basename {:?}", s),
+            NameProblem::ForbiddenCharacter(s) => write!(f, "forbidden character {:?}", s),
+            NameProblem::ForbiddenExtension(s) => write!(f, "forbidden ending {:?}", s),
+        }
+    }
+}
+
+// サーバーが受け付けないファイル名の規則。大文字小文字は区別しない
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NamingRules {
+    pub names: Vec<String>,
+    // 最初の "." より前の部分。"con" なら "con.txt" も弾かれる
+    pub basenames: Vec<String>,
+    pub characters: Vec<String>,
+    // 名前の末尾。" " や "." もここに入る
+    pub extensions: Vec<String>,
+}
+
+fn to_strings(v: &[&str]) -> Vec<String> {
+    v.iter().map(|s| s.to_string()).collect()
+}
+
+fn lowercase(v: &[String]) -> Vec<String> {
+    v.iter().map(|s| s.to_lowercase()).collect()
+}
+
+impl Default for NamingRules {
+    fn default() -> Self {
+        Self {
+            names: to_strings(DEFAULT_FORBIDDEN_NAMES),
+            basenames: Vec::new(),
+            characters: to_strings(DEFAULT_FORBIDDEN_CHARACTERS),
+            extensions: to_strings(DEFAULT_FORBIDDEN_EXTENSIONS),
+        }
+    }
+}
+
+impl NamingRules {
+    // capabilities に無い項目は既定のものを使う
+    pub fn from_capabilities(files: &FilesCapabilities) -> Self {
+        let default = Self::default();
+
+        let mut names = if files.forbidden_filenames.is_empty() {
+            default.names
+        } else {
+            lowercase(&files.forbidden_filenames)
+        };
+        for name in lowercase(&files.blacklisted_files) {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+
+        Self {
+            names,
+            basenames: lowercase(&files.forbidden_filename_basenames),
+            characters: if files.forbidden_filename_characters.is_empty() {
+                default.characters
+            } else {
+                files.forbidden_filename_characters.clone()
+            },
+            extensions: if files.forbidden_filename_extensions.is_empty() {
+                default.extensions
+            } else {
+                lowercase(&files.forbidden_filename_extensions)
+            },
+        }
+    }
+
+    // パスの要素1つについて調べる
+    pub fn check(&self, name: &str) -> Option<NameProblem> {
+        let lower = name.to_lowercase();
+
+        if let Some(n) = self.names.iter().find(|n| **n == lower) {
+            return Some(NameProblem::ForbiddenName(n.clone()));
+        }
+        let basename = lower.split('.').next().unwrap_or_default();
+        if let Some(b) = self.basenames.iter().find(|b| **b == basename) {
+            return Some(NameProblem::ForbiddenBasename(b.clone()));
+        }
+        if let Some(c) = self
+            .characters
+            .iter()
+            .find(|c| !c.is_empty() && name.contains(c.as_str()))
+        {
+            return Some(NameProblem::ForbiddenCharacter(c.clone()));
+        }
+        if let Some(e) = self
+            .extensions
+            .iter()
+            .find(|e| !e.is_empty() && lower.ends_with(e.as_str()))
+        {
+            return Some(NameProblem::ForbiddenExtension(e.clone()));
+        }
+
+        None
+    }
+
+    // 受け付けられる名前にする。直せなければ None
+    pub fn fix(&self, name: &str) -> Option<String> {
+        let mut fixed = name.to_string();
+        for c in self.characters.iter().filter(|c| !c.is_empty()) {
+            fixed = fixed.replace(c.as_str(), "_");
+        }
+        // "a. " -> "a"
+        fixed = fixed.trim_end_matches(&[' ', '.'][..]).to_string();
+        if fixed.is_empty() {
+            fixed.push('_');
+        }
+
+        // 残ったものは basename の後ろに "_" を付ける
+        for _ in 0..4 {
+            match self.check(&fixed) {
+                None => return Some(fixed),
+                Some(NameProblem::ForbiddenExtension(_)) => fixed.push('_'),
+                Some(_) => {
+                    let at = fixed
+                        .char_indices()
+                        .skip(1)
+                        .find(|(_, c)| *c == '.')
+                        .map(|(i, _)| i)
+                        .unwrap_or(fixed.len());
+                    fixed.insert(at, '_');
+                }
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn naming_rules_test() {
+        let files = FilesCapabilities {
+            blacklisted_files: vec![".htaccess".to_string()],
+            forbidden_filename_basenames: vec!["con".to_string()],
+            forbidden_filename_characters: vec!["*".to_string(), "\\".to_string()],
+            ..Default::default()
+        };
+        let rules = NamingRules::from_capabilities(&files);
+
+        assert_eq!(rules.check("report.pdf"), None);
+        assert_eq!(
+            rules.check(".HTACCESS"),
+            Some(NameProblem::ForbiddenName(".htaccess".to_string()))
+        );
+        assert_eq!(
+            rules.check("CON.txt"),
+            Some(NameProblem::ForbiddenBasename("con".to_string()))
+        );
+        assert_eq!(
+            rules.check("a*b"),
+            Some(NameProblem::ForbiddenCharacter("*".to_string()))
+        );
+        assert_eq!(
+            rules.check("notes. "),
+            Some(NameProblem::ForbiddenExtension(" ".to_string()))
+        );
+
+        assert_eq!(rules.fix("a*b\\c.txt").as_deref(), Some("a_b_c.txt"));
+        assert_eq!(rules.fix("notes. ").as_deref(), Some("notes"));
+        assert_eq!(rules.fix("con.txt").as_deref(), Some("con_.txt"));
+        assert_eq!(rules.fix("x.filepart").as_deref(), Some("x.filepart_"));
+        assert_eq!(rules.fix(".htaccess").as_deref(), Some(".htaccess_"));
+    }
+}
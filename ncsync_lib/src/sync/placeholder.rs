@@ -0,0 +1,195 @@
+use super::{resolve_local, write_atomic, LocalStamp, SyncState};
+use crate::communicate::download::download;
+use crate::errors::NcsError::*;
+use crate::path::normalize_path;
+use crate::setting::{ClientHub, FolderPair};
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// placeholder は本来のファイル名にこれを付けた名前で置く
+pub const PLACEHOLDER_SUFFIX: &str = ".ncsync";
+
+// 中身を落としていないファイルの代わりに置く。中身は JSON
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Placeholder {
+    pub remote_path: PathBuf,
+    pub etag: String,
+    pub size: u64,
+    // RFC 3339
+    pub last_modified: String,
+}
+
+impl Placeholder {
+    pub fn read(file_path: impl AsRef<Path>) -> Result<Self> {
+        let file_path = file_path.as_ref();
+        let s = fs::read_to_string(file_path)
+            .map_err(|_| NotPlaceholder(file_path.to_string_lossy().to_string()))?;
+        Ok(serde_json::from_str(&s)?)
+    }
+
+    pub fn write(&self, file_path: impl AsRef<Path>) -> Result<()> {
+        write_atomic(file_path.as_ref(), serde_json::to_string(self)?.as_bytes())
+    }
+}
+
+// "a/b.txt" -> "a/b.txt.ncsync"
+pub fn placeholder_path(local: impl AsRef<Path>) -> PathBuf {
+    let mut path = local.as_ref().as_os_str().to_owned();
+    path.push(PLACEHOLDER_SUFFIX);
+    PathBuf::from(path)
+}
+
+// 手元のファイルが ncsync の置いた placeholder か。
+// ユーザーが置いた ".ncsync" で終わるファイルは普通のファイルとして同期する
+pub(crate) fn is_placeholder(state: &SyncState, sync_path: &Path, local: &Path) -> bool {
+    let target = match sync_path
+        .to_str()
+        .and_then(|s| s.strip_suffix(PLACEHOLDER_SUFFIX))
+    {
+        Some(target) => normalize_path(Path::new(target)),
+        None => return false,
+    };
+    // state を保存する前に止まったときの placeholder も中身を読めば分かる
+    state.placeholders.contains(&target) || Placeholder::read(local).is_ok()
+}
+
+// placeholder を本来の中身に置き換える
+pub async fn hydrate(
+    client_hub: &ClientHub,
+    pair: &FolderPair,
+    state: &mut SyncState,
+    sync_path: impl AsRef<Path>,
+) -> Result<()> {
+    let sync_path = sync_path.as_ref();
+    let local = resolve_local(pair, sync_path);
+    let stub = placeholder_path(&local);
+    let placeholder = Placeholder::read(&stub)?;
+
+    let remote = pair.remote_path(&state.remote_sync_path(sync_path));
+    let bytes = download(&pair.profile, client_hub, remote).await?;
+    replace_placeholder(state, sync_path, &local, placeholder, &bytes)
+}
+
+fn replace_placeholder(
+    state: &mut SyncState,
+    sync_path: &Path,
+    local: &Path,
+    placeholder: Placeholder,
+    bytes: &[u8],
+) -> Result<()> {
+    write_atomic(local, bytes)?;
+    fs::remove_file(placeholder_path(local))?;
+
+    // etag は placeholder を置いたときのもの。その後変わっていれば次の pull で取り直す
+    state
+        .etags
+        .insert(sync_path.to_path_buf(), placeholder.etag);
+    if let Some(stamp) = LocalStamp::of(local) {
+        state.local.insert(sync_path.to_path_buf(), stamp);
+    }
+    state.placeholders.remove(sync_path);
+
+    Ok(())
+}
+
+// 同期済みのファイルを placeholder に戻して手元の容量を空ける。
+// まだリモートに上げていない変更があるときは消さずにエラーにする
+pub fn dehydrate(
+    pair: &FolderPair,
+    state: &mut SyncState,
+    sync_path: impl AsRef<Path>,
+) -> Result<()> {
+    let sync_path = sync_path.as_ref();
+    let local = resolve_local(pair, sync_path);
+
+    let stamp = LocalStamp::of(&local);
+    let etag = match state.etags.get(sync_path) {
+        Some(etag) if stamp.is_some() && stamp == state.local.get(sync_path).copied() => etag,
+        _ => return Err(UnsyncedLocalChange(local.to_string_lossy().to_string()).into()),
+    };
+
+    let metadata = fs::metadata(&local)?;
+    let placeholder = Placeholder {
+        remote_path: pair.remote_path(&state.remote_sync_path(sync_path)),
+        etag: etag.clone(),
+        size: metadata.len(),
+        last_modified: DateTime::<Local>::from(metadata.modified()?).to_rfc3339(),
+    };
+    placeholder.write(placeholder_path(&local))?;
+
+    // state.local に残すと、手元に無いので次の push でリモートから消される
+    state.local.remove(sync_path);
+    state.placeholders.insert(sync_path.to_path_buf());
+    fs::remove_file(&local)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::setting::ExcludeList;
+    use std::collections::HashMap;
+
+    #[test]
+    fn dehydrate_hydrate_test() {
+        let root =
+            std::env::temp_dir().join(format!("ncsync_placeholder_{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(root.join("a")).unwrap();
+        let pair = FolderPair::new("test", "profile", &root, "/Sync");
+        let sync_path = Path::new("/a/b.txt");
+        let local = root.join("a/b.txt");
+        fs::write(&local, "hello").unwrap();
+        // ユーザーが置いたもの
+        fs::write(root.join("notes.ncsync"), "not a placeholder").unwrap();
+
+        // 上げていない変更があれば消さない
+        let mut state = SyncState::default();
+        assert!(dehydrate(&pair, &mut state, sync_path).is_err());
+        assert!(local.exists());
+
+        state
+            .etags
+            .insert(sync_path.to_path_buf(), "etag".to_string());
+        state
+            .local
+            .insert(sync_path.to_path_buf(), LocalStamp::of(&local).unwrap());
+        dehydrate(&pair, &mut state, sync_path).unwrap();
+        assert!(!local.exists());
+        assert!(state.placeholders.contains(sync_path));
+        assert!(!state.local.contains_key(sync_path));
+        let placeholder = Placeholder::read(placeholder_path(&local)).unwrap();
+        assert_eq!(placeholder.remote_path, PathBuf::from("/Sync/a/b.txt"));
+        assert_eq!(placeholder.size, 5);
+
+        // placeholder は上げず、ユーザーの ".ncsync" は上げる
+        let mut scanned = HashMap::new();
+        let mut names = Vec::new();
+        let exclude_list = ExcludeList::default();
+        super::super::scan_local(
+            &pair,
+            &exclude_list,
+            &state,
+            &root,
+            &mut scanned,
+            &mut names,
+        )
+        .unwrap();
+        assert_eq!(
+            scanned.keys().cloned().collect::<Vec<_>>(),
+            vec![PathBuf::from("/notes.ncsync")]
+        );
+
+        replace_placeholder(&mut state, sync_path, &local, placeholder, b"hello").unwrap();
+        assert_eq!(fs::read(&local).unwrap(), b"hello");
+        assert!(!placeholder_path(&local).exists());
+        assert!(!state.placeholders.contains(sync_path));
+        assert_eq!(state.etags.get(sync_path).map(|e| e.as_str()), Some("etag"));
+        assert!(state.local.contains_key(sync_path));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
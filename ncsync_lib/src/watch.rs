@@ -0,0 +1,171 @@
+use crate::setting::{ExcludeList, IGNORE_FILE_NAME};
+use crate::sync::PARTIAL_SUFFIX;
+use anyhow::Result;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{sleep_until, Instant};
+
+const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(500);
+const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone)]
+pub struct WatchOption {
+    // 最後のイベントからこの時間何も起きなければまとめて送る
+    pub debounce: Duration,
+    // イベントが途切れなくてもこの時間で一度送る
+    pub max_delay: Duration,
+}
+
+impl Default for WatchOption {
+    fn default() -> Self {
+        Self {
+            debounce: DEFAULT_DEBOUNCE,
+            max_delay: DEFAULT_MAX_DELAY,
+        }
+    }
+}
+
+// drop すると監視をやめる
+pub struct LocalWatcher {
+    _watcher: RecommendedWatcher,
+}
+
+impl std::fmt::Debug for LocalWatcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LocalWatcher").finish()
+    }
+}
+
+// local_root からの相対パスを "/a/b" の形にする。ExcludeList はこの形で判定する
+fn to_sync_path(local_root: &Path, path: &Path) -> Option<PathBuf> {
+    let relative = path.strip_prefix(local_root).ok()?;
+    Some(Path::new("/").join(relative))
+}
+
+// 作られたばかりのディレクトリは監視が付く前に中身が書かれることがあるので中も拾う
+fn push_created_dir(dir: &Path, paths: &mut Vec<PathBuf>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            push_created_dir(&path, paths);
+        }
+        paths.push(path);
+    }
+}
+
+async fn debounce_loop(
+    mut raw: mpsc::UnboundedReceiver<PathBuf>,
+    sender: mpsc::Sender<Vec<PathBuf>>,
+    option: WatchOption,
+) {
+    loop {
+        let first = tokio::select! {
+            first = raw.recv() => first,
+            _ = sender.closed() => return,
+        };
+        let first = match first {
+            Some(path) => path,
+            None => return,
+        };
+
+        let mut paths = BTreeSet::new();
+        paths.insert(first);
+        let deadline = Instant::now() + option.max_delay;
+        let mut quiet = Instant::now() + option.debounce;
+
+        loop {
+            tokio::select! {
+                path = raw.recv() => match path {
+                    Some(path) => {
+                        paths.insert(path);
+                        quiet = Instant::now() + option.debounce;
+                    }
+                    None => break,
+                },
+                _ = sleep_until(quiet.min(deadline)) => break,
+            }
+        }
+
+        if sender.send(paths.into_iter().collect()).await.is_err() {
+            return;
+        }
+    }
+}
+
+// local_root 以下を監視し、変更されたパスをまとめて送る。
+// パスは local_root を "/" とした形で、ExcludeList で除外されるものとダウンロード途中のものは含まない。
+// tokio のランタイム上で呼ぶこと
+pub fn watch(
+    local_root: impl AsRef<Path>,
+    exclude_list: ExcludeList,
+    option: WatchOption,
+) -> Result<(LocalWatcher, mpsc::Receiver<Vec<PathBuf>>)> {
+    let local_root = local_root.as_ref().canonicalize()?;
+    // .ncsyncignore は変わるたびに読み直す
+    let base = exclude_list;
+    let mut exclude_list = base.load_ignore_files(&local_root)?;
+    let (raw_sender, raw_receiver) = mpsc::unbounded_channel();
+    let (sender, receiver) = mpsc::channel(16);
+
+    let root = local_root.clone();
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+        let event = match res {
+            Ok(event) => event,
+            Err(e) => {
+                log::warn!("watch error: {:?}", e);
+                return;
+            }
+        };
+        let mut paths = event.paths;
+        match event.kind {
+            EventKind::Access(_) => return,
+            EventKind::Create(_) => {
+                for dir in paths.clone().iter().filter(|p| p.is_dir()) {
+                    push_created_dir(dir, &mut paths);
+                }
+            }
+            _ => (),
+        }
+        for path in paths {
+            // 同期中のダウンロードが書くもの。置き換えたときの通知は daemon が無視する
+            if path.to_string_lossy().ends_with(PARTIAL_SUFFIX) {
+                continue;
+            }
+            let sync_path = match to_sync_path(&root, &path) {
+                Some(sync_path) => sync_path,
+                None => continue,
+            };
+            // 新しく対象になったものがあるかもしれないので、それ自体が除外されていても送る
+            if path.file_name() == Some(IGNORE_FILE_NAME.as_ref()) {
+                match base.load_ignore_files(&root) {
+                    Ok(reloaded) => exclude_list = reloaded,
+                    Err(e) => log::warn!("could not reload {}: {:?}", path.display(), e),
+                }
+                let _ = raw_sender.send(sync_path);
+                continue;
+            }
+            let included = if path.is_dir() {
+                exclude_list.judge_dir(&sync_path)
+            } else {
+                exclude_list.judge(&sync_path)
+            };
+            if !included {
+                continue;
+            }
+            // 受け取り側が終わっていれば何もしない
+            let _ = raw_sender.send(sync_path);
+        }
+    })?;
+    watcher.watch(&local_root, RecursiveMode::Recursive)?;
+
+    tokio::spawn(debounce_loop(raw_receiver, sender, option));
+
+    Ok((LocalWatcher { _watcher: watcher }, receiver))
+}